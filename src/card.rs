@@ -0,0 +1,390 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+/// Size of a transport file record (card) in bytes
+pub const CARD: usize = 80;
+
+/// One 80-byte transport file record
+pub type Card = [u8; CARD];
+
+/// Most cards read at a time when scanning ahead
+const SCAN_CARDS: usize = 4096;
+
+/// Looks ahead of the position of an input for the first whole card
+/// accepted by a predicate, leaving the input where it was. Returns the
+/// number of bytes in front of that card, or in front of the end of the
+/// input if no card is accepted; `None` if neither is found within the
+/// first `limit` bytes.
+type Scan<R> = fn(&mut R, u64, fn(&Card) -> bool) -> io::Result<Option<u64>>;
+
+/// 80-byte card stream with lookahead
+///
+/// Headers are read one whole card at a time, observations are read as
+/// byte records that may straddle card boundaries. Cards can be peeked
+/// or pushed back so that callers can stop in front of the next header
+/// without losing it.
+pub struct CardReader<R> {
+    inner: R,
+    /// Moves `inner` by a number of bytes, when it can seek
+    seek: Option<fn(&mut R, i64) -> io::Result<()>>,
+    /// Scans `inner` ahead for a card, when it can seek
+    scan: Option<Scan<R>>,
+    /// Length of the input from where reading started, when it can seek
+    len: Option<u64>,
+    pushed: Vec<Card>,
+    current: Card,
+    cursor: usize,
+    /// Bytes pulled from `inner` so far
+    consumed: u64,
+}
+
+impl<R: Read + Seek> CardReader<R> {
+    /// Card stream that can skip over data without reading it
+    pub fn seekable(mut inner: R) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        let end = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(CardReader {
+            seek: Some(seek_relative::<R>),
+            scan: Some(scan_by_reading::<R>),
+            len: Some(end.saturating_sub(start)),
+            ..CardReader::new(inner)
+        })
+    }
+}
+
+fn seek_relative<R: Seek>(inner: &mut R, bytes: i64) -> io::Result<()> {
+    inner.seek(SeekFrom::Current(bytes)).map(|_| ())
+}
+
+/// [`Scan`] that reads large blocks of cards, then seeks back
+fn scan_by_reading<R: Read + Seek>(
+    inner: &mut R,
+    limit: u64,
+    accept: fn(&Card) -> bool,
+) -> io::Result<Option<u64>> {
+    let cards = (limit / CARD as u64 + 1).min(SCAN_CARDS as u64) as usize;
+    let mut block = vec![0u8; cards * CARD];
+    let mut scanned = 0u64;
+    let found = loop {
+        if scanned >= limit {
+            break None;
+        }
+        let mut filled = 0;
+        while filled < block.len() {
+            match inner.read(&mut block[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let at = find_card(&block[..filled], accept);
+        scanned += filled as u64;
+        if let Some(at) = at {
+            break Some(scanned - (filled - at) as u64);
+        }
+        if filled < block.len() {
+            break Some(scanned - (filled % CARD) as u64);
+        }
+    };
+    inner.seek(SeekFrom::Current(-(scanned as i64)))?;
+    Ok(found)
+}
+
+impl<T: AsRef<[u8]>> CardReader<Cursor<T>> {
+    /// Card stream over bytes in memory, which are scanned where they are
+    /// instead of being read
+    pub fn in_memory(inner: Cursor<T>) -> Self {
+        let len = inner.get_ref().as_ref().len() as u64;
+        CardReader {
+            seek: Some(seek_relative::<Cursor<T>>),
+            scan: Some(scan_in_place::<T>),
+            len: Some(len.saturating_sub(inner.position())),
+            ..CardReader::new(inner)
+        }
+    }
+}
+
+/// [`Scan`] of bytes in memory, without copying them
+fn scan_in_place<T: AsRef<[u8]>>(
+    inner: &mut Cursor<T>,
+    limit: u64,
+    accept: fn(&Card) -> bool,
+) -> io::Result<Option<u64>> {
+    let data = inner.get_ref().as_ref();
+    let rest = data.get(inner.position() as usize..).unwrap_or_default();
+    let whole = rest.len() / CARD * CARD;
+    let window = (limit.min(whole as u64) as usize).div_ceil(CARD) * CARD;
+    let window = window.min(whole);
+    Ok(match find_card(&rest[..window], accept) {
+        Some(at) => Some(at as u64),
+        None if window == whole => Some(whole as u64),
+        None => None,
+    })
+}
+
+/// Offset of the first whole card of `data` accepted by `accept`
+fn find_card(data: &[u8], accept: fn(&Card) -> bool) -> Option<usize> {
+    data.chunks_exact(CARD)
+        .position(|card| accept(card.try_into().expect("chunk of a whole card")))
+        .map(|index| index * CARD)
+}
+
+impl<R: Read> CardReader<R> {
+    pub fn new(inner: R) -> Self {
+        CardReader {
+            inner,
+            seek: None,
+            scan: None,
+            len: None,
+            pushed: Vec::new(),
+            current: [0u8; CARD],
+            cursor: CARD,
+            consumed: 0,
+        }
+    }
+
+    /// Byte offset of the next unread byte from the start of the input
+    pub fn position(&self) -> u64 {
+        self.consumed - (self.pushed.len() * CARD) as u64 - (CARD - self.cursor) as u64
+    }
+
+    /// Whether [`skip_record_bytes`](Self::skip_record_bytes) and
+    /// [`scan_ahead`](Self::scan_ahead) are available
+    pub fn can_seek(&self) -> bool {
+        self.seek.is_some()
+    }
+
+    /// Bytes left after [`position`](Self::position), when the input can seek
+    pub fn remaining(&self) -> Option<u64> {
+        self.len.map(|len| len.saturating_sub(self.position()))
+    }
+
+    /// Reads the next whole card, discarding what is left of a partially
+    /// consumed one. Returns `None` at end of input.
+    pub fn next_card(&mut self) -> io::Result<Option<Card>> {
+        self.cursor = CARD;
+        if let Some(card) = self.pushed.pop() {
+            return Ok(Some(card));
+        }
+        self.read_raw_card()
+    }
+
+    /// Returns the next whole card without consuming it
+    pub fn peek_card(&mut self) -> io::Result<Option<&Card>> {
+        if self.pushed.is_empty() {
+            match self.read_raw_card()? {
+                Some(card) => self.pushed.push(card),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.pushed.last())
+    }
+
+    /// Puts a card back so that it is returned by the next read
+    pub fn push_back(&mut self, card: Card) {
+        self.pushed.push(card);
+    }
+
+    /// Fills `buf` from the card stream, crossing card boundaries as needed.
+    ///
+    /// Before a new card is started it is peeked and passed to `ends_record`;
+    /// if that returns true (or the input ends) the card is left in place and
+    /// reading stops. Returns the number of bytes filled, which is less than
+    /// `buf.len()` when the record was cut short.
+    pub fn read_record<F>(&mut self, buf: &mut [u8], ends_record: F) -> io::Result<usize>
+    where
+        F: Fn(&Card) -> bool,
+    {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.cursor == CARD {
+                match self.peek_card()? {
+                    None => return Ok(filled),
+                    Some(card) if ends_record(card) => return Ok(filled),
+                    Some(_) => {}
+                }
+                if let Some(card) = self.pushed.pop() {
+                    self.current = card;
+                    self.cursor = 0;
+                }
+            }
+            let take = (CARD - self.cursor).min(buf.len() - filled);
+            buf[filled..filled + take].copy_from_slice(&self.current[self.cursor..self.cursor + take]);
+            self.cursor += take;
+            filled += take;
+        }
+        Ok(filled)
+    }
+
+    /// Unread bytes of the card being read as records, empty between cards
+    pub fn card_rest(&self) -> &[u8] {
+        &self.current[self.cursor..]
+    }
+
+    /// Finds the first whole card from the next card boundary on that
+    /// `accept` returns true for, without consuming anything. Returns its
+    /// offset, or the offset where the whole cards of the input end if there
+    /// is no such card; `None` if neither turns up within `limit` bytes of
+    /// the boundary or the input cannot seek.
+    pub fn scan_ahead(&mut self, limit: u64, accept: fn(&Card) -> bool) -> io::Result<Option<u64>> {
+        let scan = match self.scan {
+            Some(scan) => scan,
+            None => return Ok(None),
+        };
+        let boundary = self.consumed - (self.pushed.len() * CARD) as u64;
+        if let Some(index) = self.pushed.iter().rev().position(accept) {
+            return Ok(Some(boundary + (index * CARD) as u64));
+        }
+        let limit = limit.saturating_sub((self.pushed.len() * CARD) as u64);
+        Ok(scan(&mut self.inner, limit, accept)?.map(|bytes| self.consumed + bytes))
+    }
+
+    /// Skips `n` bytes of record data by seeking. Only the card holding the
+    /// last skipped byte is read, which checks that the input is long enough.
+    /// Returns `false`, having changed nothing, if the input cannot seek, is
+    /// too short, or the skip ends within cards already read.
+    pub fn skip_record_bytes(&mut self, n: u64) -> io::Result<bool> {
+        let seek = match self.seek {
+            Some(seek) if n > 0 => seek,
+            _ => return Ok(false),
+        };
+        let target = self.position() + n;
+        let card_start = (target - 1) / CARD as u64 * CARD as u64;
+        let origin = self.consumed;
+        if card_start < origin {
+            return Ok(false);
+        }
+        seek(&mut self.inner, (card_start - origin) as i64)?;
+        self.consumed = card_start;
+        match self.read_raw_card() {
+            Ok(Some(card)) => {
+                self.pushed.clear();
+                self.current = card;
+                self.cursor = (target - card_start) as usize;
+                Ok(true)
+            }
+            Ok(None) => self.seek_back(seek, origin),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => self.seek_back(seek, origin),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns to `origin` after a skip that ran past the end of the input
+    fn seek_back(&mut self, seek: fn(&mut R, i64) -> io::Result<()>, origin: u64) -> io::Result<bool> {
+        seek(&mut self.inner, -((self.consumed - origin) as i64))?;
+        self.consumed = origin;
+        Ok(false)
+    }
+
+    fn read_raw_card(&mut self) -> io::Result<Option<Card>> {
+        let mut card = [0u8; CARD];
+        let mut filled = 0;
+        while filled < CARD {
+            match self.inner.read(&mut card[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.consumed += filled as u64;
+        match filled {
+            0 => Ok(None),
+            CARD => Ok(Some(card)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Truncated card: {} of {} bytes", filled, CARD),
+            )),
+        }
+    }
+}
+
+/// 80-byte card stream being written
+///
+/// Headers are written as whole cards, observations as byte records that
+/// may straddle card boundaries; [`finish_card`](Self::finish_card) pads
+/// the last card with blanks. Output that can seek also allows bytes
+/// already written to be [overwritten](Self::overwrite).
+pub struct CardWriter<W> {
+    inner: W,
+    /// Moves `inner` by a number of bytes, when it can seek
+    seek: Option<fn(&mut W, i64) -> io::Result<()>>,
+    /// Bytes written to `inner` so far
+    written: u64,
+}
+
+impl<W: Write + Seek> CardWriter<W> {
+    /// Card stream that can go back to patch what it has written
+    pub fn seekable(inner: W) -> Self {
+        CardWriter {
+            seek: Some(seek_relative::<W>),
+            ..CardWriter::new(inner)
+        }
+    }
+}
+
+impl<W: Write> CardWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CardWriter {
+            inner,
+            seek: None,
+            written: 0,
+        }
+    }
+
+    /// Whether [`overwrite`](Self::overwrite) is available
+    pub fn can_seek(&self) -> bool {
+        self.seek.is_some()
+    }
+
+    /// Byte offset of the next byte from the start of the output
+    pub fn position(&self) -> u64 {
+        self.written
+    }
+
+    /// Writes a whole card, after padding any partly written one
+    pub fn write_card(&mut self, card: &Card) -> io::Result<()> {
+        self.finish_card()?;
+        self.write_record(card)
+    }
+
+    /// Writes record bytes, crossing card boundaries as needed
+    pub fn write_record(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Fills the rest of the current card with blanks
+    pub fn finish_card(&mut self) -> io::Result<()> {
+        let used = (self.written % CARD as u64) as usize;
+        if used > 0 {
+            self.write_record(&[b' '; CARD][used..])?;
+        }
+        Ok(())
+    }
+
+    /// Replaces bytes already written at `offset`, then returns to the end
+    /// of the output
+    pub fn overwrite(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let seek = self
+            .seek
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "output cannot seek"))?;
+        let back = self.written - offset;
+        debug_assert!(bytes.len() as u64 <= back, "overwrite past the end of the output");
+        seek(&mut self.inner, -(back as i64))?;
+        self.inner.write_all(bytes)?;
+        seek(&mut self.inner, (back - bytes.len() as u64) as i64)
+    }
+
+    /// Pads the last card and flushes the output
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finish_card()?;
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+
+use crate::error::in_member;
+
+/// How serious a [`Diagnostic`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Not a problem, e.g. a choice made while reading
+    Info,
+    /// A deviation from TS-140 that was recovered from without losing data
+    Warning,
+    /// A deviation that was skipped over, losing the data concerned
+    Error,
+}
+
+/// A problem found and recovered from while reading in lenient mode, or
+/// while writing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Byte offset from the start of the file
+    pub offset: u64,
+    /// Member being read or written when the problem was found, if any
+    pub member: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at byte {}{}: {}",
+            self.severity,
+            self.offset,
+            in_member(&self.member),
+            self.message
+        )
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors raised while reading or writing a transport file
+///
+/// Offsets are byte positions from the start of the file; `member` names
+/// the dataset being read when the problem was found, if any.
+#[derive(Debug, Error)]
+pub enum XptError {
+    /// The input does not start like a SAS transport file
+    #[error("not a SAS transport file: {0}")]
+    NotXpt(String),
+
+    /// The input ends in the middle of a header or record
+    #[error("file truncated at byte {offset}{}: {message}", in_member(.member))]
+    Truncated {
+        offset: u64,
+        member: Option<String>,
+        message: String,
+    },
+
+    /// A header record is missing, out of place or malformed
+    #[error("bad header at byte {offset}{}: {message}", in_member(.member))]
+    BadHeader {
+        offset: u64,
+        member: Option<String>,
+        message: String,
+    },
+
+    /// The observation data does not match the width declared by the NAMESTR records
+    #[error("inconsistent row width at byte {offset} in member {member}: {message}")]
+    InconsistentRowWidth {
+        offset: u64,
+        member: String,
+        message: String,
+    },
+
+    /// A transport variant this crate cannot read (e.g. CPORT files)
+    #[error("unsupported transport format: {0}")]
+    UnsupportedVersion(String),
+
+    /// Metadata or values that cannot be written to a transport file
+    #[error("cannot write{}: {message}", in_member(.member))]
+    Unwritable {
+        member: Option<String>,
+        message: String,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, XptError>;
+
+pub(crate) fn in_member(member: &Option<String>) -> String {
+    match member {
+        Some(name) => format!(" in member {}", name),
+        None => String::new(),
+    }
+}
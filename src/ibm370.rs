@@ -1,39 +1,473 @@
-use std::f64;
+use thiserror::Error;
 
-pub enum IbmMissing { Dot, Letter(u8), None }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbmMissing { Dot, Underscore, Letter(u8), None }
 
+/// A value with no IBM 360 double representation
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum IbmEncodeError {
+    /// Larger in magnitude than the largest IBM double (about 7.2e75), or infinite
+    #[error("{0:e} is too large for an IBM double")]
+    Overflow(f64),
+    /// Nonzero but smaller in magnitude than the smallest normalised IBM
+    /// double (about 5.4e-79)
+    #[error("{0:e} is too small for an IBM double")]
+    Underflow(f64),
+    /// NaN, which can only be written as a missing value
+    #[error("NaN has no IBM double encoding")]
+    NotANumber,
+}
+
+/// Full length of an IBM 360 double in bytes
+const IBM64_LENGTH: usize = 8;
+/// Bits of the 56-bit fraction
+const FRACTION_MASK: u64 = (1 << 56) - 1;
+/// Bits of the f64 mantissa below the implicit leading 1
+const MANTISSA_MASK: u64 = (1 << 52) - 1;
+
+/// Converts an IBM 360 double to f64
+///
+/// `bytes` may be truncated to fewer than 8 bytes, as SAS stores numerics
+/// of length 3-7: the missing low-order bytes are taken as zeros. Missing
+/// values (`.`, `._`, `.A`-`.Z`) are reported in the second element.
 pub fn ibm64_to_f64(bytes: &[u8]) -> (Option<f64>, IbmMissing) {
-    if bytes.len() < 8 { return (None, IbmMissing::None); }
-    let b0 = bytes[0];
-
-    if bytes[1..].iter().all(|&v| v == 0x00) {
-        return match b0 {
-            0x2E | 0x5F => (None, IbmMissing::Dot),
-            0x41..=0x5A => (None, IbmMissing::Letter(b0)),
-            _ => (None, IbmMissing::None),
+    if bytes.is_empty() { return (None, IbmMissing::None); }
+    let bits = ibm64_bits(bytes);
+    match missing_kind(bits) {
+        IbmMissing::None => (Some(bits_to_f64(bits)), IbmMissing::None),
+        kind => (None, kind),
+    }
+}
+
+/// Kind of missing value held in `bytes`, or [`IbmMissing::None`] for a number
+pub fn ibm64_missing(bytes: &[u8]) -> IbmMissing {
+    missing_kind(ibm64_bits(bytes))
+}
+
+/// Converts a column of IBM 360 doubles, each `width` bytes long (1-8),
+/// into `out`, which must hold exactly one f64 per value
+///
+/// Missing values become NaN; [`ibm64_missing`] tells their kinds apart.
+/// Returns the number of missing values.
+///
+/// # Panics
+///
+/// If `width` is not 1-8 or `column` does not hold `out.len()` values.
+pub fn ibm64_column_to_f64(column: &[u8], width: usize, out: &mut [f64]) -> usize {
+    ibm64_strided_to_f64(column, width, 0, width, out)
+}
+
+/// Converts the IBM 360 doubles found at `offset..offset + width` of each
+/// `row_len`-byte row of `rows`, as in the observations of a transport
+/// file, into `out`, which must hold exactly one f64 per row
+///
+/// Missing values become NaN, as with [`ibm64_column_to_f64`]. Returns the
+/// number of missing values.
+///
+/// # Panics
+///
+/// If `width` is not 1-8, the value does not fit in a row, or `rows` does
+/// not hold `out.len()` rows.
+pub fn ibm64_strided_to_f64(rows: &[u8], row_len: usize, offset: usize, width: usize, out: &mut [f64]) -> usize {
+    assert!((1..=IBM64_LENGTH).contains(&width), "IBM double width must be 1-8 bytes, not {}", width);
+    assert!(offset + width <= row_len, "bytes {}..{} do not fit in a {}-byte row", offset, offset + width, row_len);
+    assert_eq!(rows.len(), row_len * out.len(), "data length does not match {} rows of {} bytes", out.len(), row_len);
+    let mut missing = 0;
+    for (row, out) in rows.chunks_exact(row_len).zip(out.iter_mut()) {
+        let bits = ibm64_bits(&row[offset..offset + width]);
+        *out = match missing_kind(bits) {
+            IbmMissing::None => bits_to_f64(bits),
+            _ => {
+                missing += 1;
+                f64::NAN
+            }
+        };
+    }
+    missing
+}
+
+/// Converts an f64 to an IBM 360 double
+///
+/// The conversion is exact for every value in the IBM range: aligned to a
+/// hexadecimal exponent, the 53-bit mantissa takes at most 56 bits, which
+/// is what the fraction holds. Precision is only lost the other way, when
+/// [`ibm64_to_f64`] rounds a fraction of more than 53 bits.
+pub fn f64_to_ibm64(value: f64) -> Result<[u8; 8], IbmEncodeError> {
+    f64_to_ibm(value, IBM64_LENGTH)
+}
+
+/// Converts an f64 to an IBM 360 double stored in `length` bytes (2-8)
+///
+/// The value is rounded to nearest, ties to even, at the precision of the
+/// `length - 1` fraction bytes; it occupies the first `length` bytes of the
+/// result and the rest are zero. Zeros of either sign encode as all zeros.
+///
+/// # Panics
+///
+/// If `length` is not 2-8.
+pub fn f64_to_ibm(value: f64, length: usize) -> Result<[u8; 8], IbmEncodeError> {
+    assert!((2..=IBM64_LENGTH).contains(&length), "IBM double length must be 2-8 bytes, not {}", length);
+    if value.is_nan() { return Err(IbmEncodeError::NotANumber); }
+    if value.is_infinite() { return Err(IbmEncodeError::Overflow(value)); }
+    if value == 0.0 { return Ok([0; IBM64_LENGTH]); }
+
+    let bits = value.to_bits();
+    let sign = (bits >> 63) as u8;
+    let biased = ((bits >> 52) & 0x7FF) as i32;
+    // f64 subnormals are far below the IBM range
+    if biased == 0 { return Err(IbmEncodeError::Underflow(value)); }
+    // value = mantissa * 2^exp, with the leading 1 of the 53-bit mantissa at 2^(exp + 52)
+    let mantissa = (bits & MANTISSA_MASK) | (1 << 52);
+    let exp = biased - 1075;
+
+    // value = 0.frac * 16^p, with the fraction normalised so that its
+    // leading hex digit is nonzero
+    let frac_bits = 8 * (length as i32 - 1);
+    let mut p = (exp + 52).div_euclid(4) + 1;
+    let shift = exp - 4 * p + frac_bits;
+    let mut frac = if shift >= 0 {
+        mantissa << shift
+    } else {
+        let shift = -shift;
+        let dropped = mantissa & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let kept = mantissa >> shift;
+        if dropped > half || (dropped == half && kept & 1 == 1) { kept + 1 } else { kept }
+    };
+    if frac == 1 << frac_bits {
+        // Rounding carried into a new leading hex digit
+        frac >>= 4;
+        p += 1;
+    }
+    if p > 63 { return Err(IbmEncodeError::Overflow(value)); }
+    if p < -64 { return Err(IbmEncodeError::Underflow(value)); }
+
+    let mut out = [0u8; IBM64_LENGTH];
+    out[0] = sign << 7 | (p + 64) as u8;
+    out[1..length].copy_from_slice(&frac.to_be_bytes()[8 - (length - 1)..]);
+    Ok(out)
+}
+
+/// Bytes of a missing value: `.`, `._` or `.A`-`.Z`
+///
+/// Returns `None` for [`IbmMissing::None`] and for letters outside `A`-`Z`.
+pub fn missing_to_ibm64(kind: IbmMissing) -> Option<[u8; 8]> {
+    let code = match kind {
+        IbmMissing::Dot => 0x2E,
+        IbmMissing::Underscore => 0x5F,
+        IbmMissing::Letter(letter @ b'A'..=b'Z') => letter,
+        IbmMissing::Letter(_) | IbmMissing::None => return None,
+    };
+    let mut out = [0u8; IBM64_LENGTH];
+    out[0] = code;
+    Some(out)
+}
+
+/// Big-endian bits of a possibly truncated value, zero-filled to 8 bytes
+fn ibm64_bits(bytes: &[u8]) -> u64 {
+    let mut full = [0u8; IBM64_LENGTH];
+    let stored = bytes.len().min(IBM64_LENGTH);
+    full[..stored].copy_from_slice(&bytes[..stored]);
+    u64::from_be_bytes(full)
+}
+
+/// Missing values are a code in the first byte followed by zeros
+fn missing_kind(bits: u64) -> IbmMissing {
+    if bits & FRACTION_MASK != 0 { return IbmMissing::None; }
+    match (bits >> 56) as u8 {
+        0x2E => IbmMissing::Dot,
+        0x5F => IbmMissing::Underscore,
+        letter @ 0x41..=0x5A => IbmMissing::Letter(letter),
+        _ => IbmMissing::None,
+    }
+}
+
+/// Builds the f64 bit pattern of a non-missing IBM double
+///
+/// value = 0.frac (56 bits) * 16^p = frac * 2^(4p - 56). The fraction is
+/// normalised to f64's 53-bit mantissa, rounding to nearest with ties to
+/// even, which is the only inexact step: every IBM exponent lands in the
+/// normal f64 range, so no value overflows or becomes subnormal.
+fn bits_to_f64(bits: u64) -> f64 {
+    let frac = bits & FRACTION_MASK;
+    if frac == 0 { return 0.0; }
+    let sign = bits & (1 << 63);
+    let p = ((bits >> 56) & 0x7F) as i32 - 64;
+
+    // Position of the leading 1 bit of the fraction, 0-55
+    let top = 63 - frac.leading_zeros() as i32;
+    let mut exp = top + 4 * p - 56;
+    let mut mantissa = if top > 52 {
+        let shift = top - 52;
+        let dropped = frac & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let kept = frac >> shift;
+        if dropped > half || (dropped == half && kept & 1 == 1) { kept + 1 } else { kept }
+    } else {
+        frac << (52 - top)
+    };
+    if mantissa == 1 << 53 {
+        // Rounding carried into a new leading bit
+        mantissa >>= 1;
+        exp += 1;
+    }
+    f64::from_bits(sign | ((exp + 1023) as u64) << 52 | (mantissa & MANTISSA_MASK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> f64 {
+        match ibm64_to_f64(bytes) {
+            (Some(value), IbmMissing::None) => value,
+            other => panic!("{:02X?} decoded as {:?}", bytes, other),
         }
     }
 
-    let sign = (b0 & 0x80) != 0;
-    let exp  = (b0 & 0x7F) as i32;
-    if exp == 0 && bytes[1..].iter().all(|&v| v == 0) {
-        return (Some(0.0), IbmMissing::None);
+    /// Tiny deterministic generator, so that failures can be replayed
+    fn patterns(count: usize) -> impl Iterator<Item = u64> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..count).map(move |_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            state
+        })
+    }
+
+    #[test]
+    fn zeros_decode_to_zero() {
+        assert_eq!(decode(&[0; 8]), 0.0);
+        assert_eq!(decode(&[0x80, 0, 0, 0, 0, 0, 0, 0]), 0.0);
+        // Any exponent with a zero fraction is zero, except missing codes
+        assert_eq!(decode(&[0x20, 0, 0, 0, 0, 0, 0, 0]), 0.0);
+    }
+
+    #[test]
+    fn simple_values() {
+        assert_eq!(decode(&[0x41, 0x10, 0, 0, 0, 0, 0, 0]), 1.0);
+        assert_eq!(decode(&[0xC2, 0x64, 0x80, 0, 0, 0, 0, 0]), -100.5);
+        assert_eq!(decode(&[0x41, 0x80, 0, 0, 0, 0, 0, 0]), 8.0);
+        assert_eq!(decode(&[0x40, 0x80, 0, 0, 0, 0, 0, 0]), 0.5);
     }
-    let p = exp - 64;
 
-    let mut frac_u: u64 = 0;
-    for &bb in &bytes[1..8] { frac_u = (frac_u << 8) | bb as u64; }
+    #[test]
+    fn unnormalized_fractions() {
+        // Leading hex digits of zero, as some writers leave them
+        assert_eq!(decode(&[0x41, 0x01, 0, 0, 0, 0, 0, 0]), 0.0625);
+        assert_eq!(decode(&[0x42, 0x00, 0x10, 0, 0, 0, 0, 0]), 0.0625);
+        assert_eq!(decode(&[0x4E, 0, 0, 0, 0, 0, 0, 0x01]), 1.0);
+    }
+
+    #[test]
+    fn extreme_exponents() {
+        assert_eq!(decode(&[0x00, 0x10, 0, 0, 0, 0, 0, 0]), 2f64.powi(-260));
+        assert_eq!(decode(&[0x00, 0, 0, 0, 0, 0, 0, 0x01]), 2f64.powi(-312));
+        // 56 one bits round up to the next power of two
+        assert_eq!(decode(&[0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]), 2f64.powi(252));
+        assert_eq!(decode(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]), -(2f64.powi(252)));
+    }
+
+    #[test]
+    fn rounds_to_nearest_even() {
+        // A 56-bit fraction with its top bit set loses 3 bits; 0x4 of them
+        // is exactly half, rounded to the even mantissa either way
+        let down = decode(&[0x4E, 0x80, 0, 0, 0, 0, 0, 0x04]);
+        assert_eq!(down, 2f64.powi(55));
+        let up = decode(&[0x4E, 0x80, 0, 0, 0, 0, 0, 0x0C]);
+        assert_eq!(up, 2f64.powi(55) + 16.0);
+        let above = decode(&[0x4E, 0x80, 0, 0, 0, 0, 0, 0x05]);
+        assert_eq!(above, 2f64.powi(55) + 8.0);
+    }
+
+    #[test]
+    fn matches_exact_arithmetic() {
+        // frac as f64 is the only rounding step; scaling by a power of two
+        // is exact over the whole IBM range
+        for bits in patterns(100_000) {
+            let bytes = bits.to_be_bytes();
+            if ibm64_missing(&bytes) != IbmMissing::None {
+                continue;
+            }
+            let frac = (bits & FRACTION_MASK) as f64;
+            let p = ((bits >> 56) & 0x7F) as i32 - 64;
+            let mut expected = frac * 2f64.powi(4 * p - 56);
+            if bits >> 63 == 1 {
+                expected = -expected;
+            }
+            assert_eq!(decode(&bytes).to_bits(), (expected + 0.0).to_bits(), "{:016X}", bits);
+        }
+    }
 
-    let mut f = 0.0f64;
-    let mut denom = 1.0f64;
-    let mut tmp = frac_u;
-    for _ in 0..14 {
-        let nib = (tmp >> 52) & 0xF;
-        f += (nib as f64) / denom;
-        denom *= 16.0;
-        tmp <<= 4;
+    #[test]
+    fn truncated_lengths_read_as_zero_filled() {
+        let full = [0x41, 0x3A, 0x5B, 0x7C, 0x9D, 0xBE, 0xDF, 0xF1];
+        for length in 3..=7 {
+            let mut filled = [0u8; 8];
+            filled[..length].copy_from_slice(&full[..length]);
+            assert_eq!(decode(&full[..length]), decode(&filled), "length {}", length);
+        }
+        assert_eq!(decode(&[0x41, 0x10, 0x00]), 1.0);
+        assert_eq!(ibm64_to_f64(&[]), (None, IbmMissing::None));
+    }
+
+    #[test]
+    fn every_missing_code() {
+        let mut codes = vec![(0x2E, IbmMissing::Dot), (0x5F, IbmMissing::Underscore)];
+        codes.extend((b'A'..=b'Z').map(|letter| (letter, IbmMissing::Letter(letter))));
+        for (code, kind) in codes {
+            let bytes = [code, 0, 0, 0, 0, 0, 0, 0];
+            assert_eq!(ibm64_to_f64(&bytes), (None, kind));
+            assert_eq!(ibm64_missing(&bytes), kind);
+            // Truncated missing values are still missing
+            assert_eq!(ibm64_missing(&bytes[..3]), kind);
+        }
+        // A nonzero fraction makes it a number
+        assert_eq!(ibm64_missing(&[0x2E, 0, 0, 0, 0, 0, 0, 0x01]), IbmMissing::None);
+    }
+
+    #[test]
+    fn column_conversion() {
+        let column = [
+            0x41, 0x10, 0, 0, //
+            0x2E, 0, 0, 0, //
+            0xC2, 0x64, 0x80, 0, //
+            0x5A, 0, 0, 0,
+        ];
+        let mut out = [0.0; 4];
+        assert_eq!(ibm64_column_to_f64(&column, 4, &mut out), 2);
+        assert_eq!(out[0], 1.0);
+        assert!(out[1].is_nan());
+        assert_eq!(out[2], -100.5);
+        assert!(out[3].is_nan());
+    }
+
+    #[test]
+    fn strided_conversion() {
+        // 8-byte rows: a 2-byte tag, then a 4-byte value, then 2 more bytes
+        let rows = [
+            b'a', b'b', 0x41, 0x10, 0, 0, b'y', b'z', //
+            b'c', b'd', 0x2E, 0, 0, 0, b'y', b'z', //
+            b'e', b'f', 0xC2, 0x64, 0x80, 0, b'y', b'z',
+        ];
+        let mut out = [0.0; 3];
+        assert_eq!(ibm64_strided_to_f64(&rows, 8, 2, 4, &mut out), 1);
+        assert_eq!(out[0], 1.0);
+        assert!(out[1].is_nan());
+        assert_eq!(out[2], -100.5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn strided_value_must_fit_in_a_row() {
+        ibm64_strided_to_f64(&[0; 16], 8, 4, 8, &mut [0.0; 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn column_length_must_match() {
+        ibm64_column_to_f64(&[0; 12], 8, &mut [0.0; 2]);
+    }
+
+    #[test]
+    fn encodes_simple_values() {
+        assert_eq!(f64_to_ibm64(1.0), Ok([0x41, 0x10, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm64(-100.5), Ok([0xC2, 0x64, 0x80, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm64(0.0), Ok([0; 8]));
+        assert_eq!(f64_to_ibm64(-0.0), Ok([0; 8]));
+    }
+
+    #[test]
+    fn f64_round_trips_exactly() {
+        for bits in patterns(100_000) {
+            let value = f64::from_bits(bits);
+            if let Ok(ibm) = f64_to_ibm64(value) {
+                assert_eq!(decode(&ibm).to_bits(), (value + 0.0).to_bits(), "{:e}", value);
+            }
+        }
+        let near_one = 1.0 - f64::EPSILON / 2.0;
+        assert_eq!(decode(&f64_to_ibm64(near_one).unwrap()), near_one);
+    }
+
+    #[test]
+    fn rounding_carries_into_the_exponent() {
+        // 0.FFFF... rounds up to 1.0, which needs the next exponent
+        assert_eq!(f64_to_ibm(0.999_999_9, 2), Ok([0x41, 0x10, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm(-0.999_999_999_9, 4), Ok([0xC1, 0x10, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn rounds_ties_to_even() {
+        // Half of the last fraction bit at length 2, below an even and an
+        // odd fraction byte
+        assert_eq!(f64_to_ibm(128.5 / 256.0, 2), Ok([0x40, 0x80, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm(129.5 / 256.0, 2), Ok([0x40, 0x82, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm(129.4 / 256.0, 2), Ok([0x40, 0x81, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn truncated_lengths_round_the_full_fraction() {
+        for bits in patterns(20_000) {
+            let value = f64::from_bits(bits);
+            let full = match f64_to_ibm64(value) {
+                Ok(full) => u64::from_be_bytes(full),
+                Err(_) => continue,
+            };
+            for length in 2..=8 {
+                let short = match f64_to_ibm(value, length) {
+                    Ok(short) => short,
+                    // Rounding up the largest values overflows
+                    Err(IbmEncodeError::Overflow(_)) => continue,
+                    Err(e) => panic!("{:e} at length {}: {}", value, length, e),
+                };
+                assert!(short[length..].iter().all(|&b| b == 0));
+                // The kept fraction is within half a unit of the exact one
+                let dropped = 8 * (8 - length) as i32;
+                let exact = decode(&full.to_be_bytes());
+                let unit = 2f64.powi(4 * (((full >> 56) & 0x7F) as i32 - 64) - 56 + dropped);
+                let error = (decode(&short[..length]) - exact).abs();
+                assert!(error <= unit / 2.0, "{:e} at length {}", value, length);
+            }
+        }
+        assert_eq!(f64_to_ibm(1.0 / 3.0, 3), Ok([0x40, 0x55, 0x55, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm(2.0 / 3.0, 3), Ok([0x40, 0xAA, 0xAB, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn out_of_range_values_are_refused() {
+        let largest = 2f64.powi(252) - 2f64.powi(199);
+        assert_eq!(f64_to_ibm64(largest), Ok([0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xF8]));
+        assert_eq!(f64_to_ibm64(2f64.powi(252)), Err(IbmEncodeError::Overflow(2f64.powi(252))));
+        assert_eq!(f64_to_ibm(largest, 4), Err(IbmEncodeError::Overflow(largest)));
+        assert_eq!(
+            f64_to_ibm64(f64::NEG_INFINITY),
+            Err(IbmEncodeError::Overflow(f64::NEG_INFINITY))
+        );
+
+        let smallest = 2f64.powi(-260);
+        assert_eq!(f64_to_ibm64(smallest), Ok([0x00, 0x10, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(f64_to_ibm64(smallest / 2.0), Err(IbmEncodeError::Underflow(smallest / 2.0)));
+        let subnormal = f64::from_bits(1);
+        assert_eq!(f64_to_ibm64(subnormal), Err(IbmEncodeError::Underflow(subnormal)));
+
+        assert_eq!(f64_to_ibm64(f64::NAN), Err(IbmEncodeError::NotANumber));
+    }
+
+    #[test]
+    #[should_panic]
+    fn length_must_be_two_to_eight() {
+        let _ = f64_to_ibm(1.0, 1);
+    }
+
+    #[test]
+    fn missing_value_bytes() {
+        assert_eq!(missing_to_ibm64(IbmMissing::Dot), Some([0x2E, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(missing_to_ibm64(IbmMissing::Underscore), Some([0x5F, 0, 0, 0, 0, 0, 0, 0]));
+        for letter in b'A'..=b'Z' {
+            let bytes = missing_to_ibm64(IbmMissing::Letter(letter)).unwrap();
+            assert_eq!(bytes, [letter, 0, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(ibm64_missing(&bytes), IbmMissing::Letter(letter));
+        }
+        assert_eq!(missing_to_ibm64(IbmMissing::Letter(b'a')), None);
+        assert_eq!(missing_to_ibm64(IbmMissing::None), None);
     }
-    let mut val = f * 16f64.powi(p);
-    if sign { val = -val; }
-    (Some(val), IbmMissing::None)
-}
\ No newline at end of file
+}
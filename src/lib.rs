@@ -1,62 +1,365 @@
+pub mod diagnostic;
+pub mod error;
 pub mod ibm370;
-pub mod xpt_parser;
+pub mod mapped;
+pub mod text;
+pub mod value;
+pub mod writer;
+pub mod xpt;
+mod card;
+mod parallel;
 
-pub use ibm370::{ibm64_to_f64, IbmMissing};
+pub use diagnostic::{Diagnostic, Severity};
+pub use error::XptError;
+pub use ibm370::{
+    f64_to_ibm, f64_to_ibm64, ibm64_column_to_f64, ibm64_missing, ibm64_strided_to_f64, ibm64_to_f64,
+    missing_to_ibm64, IbmEncodeError, IbmMissing,
+};
+pub use encoding_rs::Encoding;
+pub use mapped::{MappedMember, MappedXpt};
+pub use text::TextEncoding;
+pub use value::{MissingKind, NumberFormat, Value};
+pub use writer::{write_xpt_to_writer, write_xpt_to_writer_with, MemberWriter, XptWriter};
+pub use xpt::{read_xpt_v5_from_reader, read_xpt_v5_from_reader_with, MemberReader, XptReader};
 
-use anyhow::Result;
-use std::fs;
+use error::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 
 /// Dataset structure matching the expected API
 #[derive(Debug, Clone)]
 pub struct Dataset {
     pub name: String,
+    pub member: MemberInfo,
+    pub library: LibraryInfo,
     pub vars: Vec<VarMeta>,
-    pub rows: Vec<Vec<Option<String>>>,
+    pub rows: Vec<Vec<Value>>,
 }
 
-/// Variable metadata matching the expected API
+impl Dataset {
+    /// Dataset with default member and library metadata, e.g. for writing
+    pub fn new(name: impl Into<String>, vars: Vec<VarMeta>, rows: Vec<Vec<Value>>) -> Self {
+        let name = name.into();
+        Dataset {
+            member: MemberInfo {
+                name: name.clone(),
+                ..Default::default()
+            },
+            name,
+            library: LibraryInfo::default(),
+            vars,
+            rows,
+        }
+    }
+
+    /// String view of a row (see [`Value::to_string_opt`])
+    pub fn string_row(&self, index: usize) -> Option<Vec<Option<String>>> {
+        self.rows
+            .get(index)
+            .map(|row| row.iter().map(Value::to_string_opt).collect())
+    }
+
+    /// String view of all rows, in order
+    pub fn string_rows(&self) -> impl Iterator<Item = Vec<Option<String>>> + '_ {
+        self.string_rows_with(NumberFormat::default())
+    }
+
+    /// String view of all rows with numbers rendered using `format`
+    pub fn string_rows_with(
+        &self,
+        format: NumberFormat,
+    ) -> impl Iterator<Item = Vec<Option<String>>> + '_ {
+        self.rows
+            .iter()
+            .map(move |row| row.iter().map(|v| v.to_string_with(format)).collect())
+    }
+}
+
+/// Transport file layout version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XptVersion {
+    /// Version 5/6: 8-character names, 40-character labels
+    #[default]
+    V5,
+    /// Version 8/9: 32-character names, long labels and formats
+    V8,
+}
+
+/// How deviations from TS-140 are handled while reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Any deviation is an error
+    Strict,
+    /// Deviations are recovered from where possible and reported as
+    /// [`Diagnostic`]s
+    #[default]
+    Lenient,
+}
+
+/// Options controlling how transport files are read
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub mode: ParseMode,
+    /// Encoding of labels and character values
+    pub encoding: TextEncoding,
+    /// Variables to decode; the others are skipped without being decoded
+    pub columns: Columns,
+    /// Observations to skip at the start of each member, without decoding
+    /// them
+    pub skip: usize,
+    /// Maximum number of observations to read from each member
+    pub limit: Option<usize>,
+    /// Threads used to decode observations when whole members or columns
+    /// are read at once
+    pub parallelism: Parallelism,
+}
+
+impl ReadOptions {
+    /// Options that reject any deviation from TS-140
+    pub fn strict() -> Self {
+        ReadOptions {
+            mode: ParseMode::Strict,
+            ..Default::default()
+        }
+    }
+}
+
+/// How many threads decode observations
+///
+/// Rows are fixed-width and independent, so a batch of raw rows is split
+/// into contiguous chunks decoded side by side. The rows always come back
+/// in file order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parallelism {
+    /// Decode on the calling thread
+    #[default]
+    Sequential,
+    /// Decode on up to this many threads
+    Threads(usize),
+    /// One thread per available core
+    Available,
+}
+
+impl Parallelism {
+    /// Number of threads to decode with, at least 1
+    pub fn threads(self) -> usize {
+        match self {
+            Parallelism::Sequential => 1,
+            Parallelism::Threads(threads) => threads.max(1),
+            Parallelism::Available => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        }
+    }
+}
+
+/// Options controlling how transport files are written
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Layout to write: V5 (the default) or V8 with long names and labels
+    pub version: XptVersion,
+    /// Encoding of labels and character values
+    pub encoding: &'static Encoding,
+    /// Truncate names and labels too long for `version`, with a warning,
+    /// instead of refusing to write them
+    pub truncate: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            version: XptVersion::V5,
+            encoding: encoding_rs::UTF_8,
+            truncate: false,
+        }
+    }
+}
+
+/// Selection of the variables to read
+#[derive(Clone, Default)]
+pub enum Columns {
+    #[default]
+    All,
+    /// Variables with these names (case-insensitive), in this order. Names
+    /// missing from a member are reported as warnings.
+    Names(Vec<String>),
+    /// Variables for which the predicate holds, in file order
+    Filter(Arc<dyn Fn(&VarMeta) -> bool + Send + Sync>),
+}
+
+impl Columns {
+    pub fn names<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Columns::Names(names.into_iter().map(Into::into).collect())
+    }
+
+    pub fn filter<F>(predicate: F) -> Self
+    where
+        F: Fn(&VarMeta) -> bool + Send + Sync + 'static,
+    {
+        Columns::Filter(Arc::new(predicate))
+    }
+
+    /// Picks the selected variables of a member, returning them together
+    /// with the requested names that it does not have
+    pub(crate) fn select(&self, vars: Vec<VarMeta>) -> (Vec<VarMeta>, Vec<String>) {
+        match self {
+            Columns::All => (vars, Vec::new()),
+            Columns::Names(names) => {
+                let mut selected = Vec::with_capacity(names.len());
+                let mut missing = Vec::new();
+                for name in names {
+                    match vars.iter().find(|v| v.name.eq_ignore_ascii_case(name)) {
+                        Some(var) => selected.push(var.clone()),
+                        None => missing.push(name.clone()),
+                    }
+                }
+                (selected, missing)
+            }
+            Columns::Filter(predicate) => {
+                (vars.into_iter().filter(|v| predicate(v)).collect(), Vec::new())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Columns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Columns::All => write!(f, "All"),
+            Columns::Names(names) => f.debug_tuple("Names").field(names).finish(),
+            Columns::Filter(_) => write!(f, "Filter(..)"),
+        }
+    }
+}
+
+/// Library-level metadata from the LIBRARY header records
+#[derive(Debug, Clone, Default)]
+pub struct LibraryInfo {
+    pub version: XptVersion,
+    pub sas_version: String,
+    pub os: String,
+    /// Creation datetime as written, e.g. `16OCT26:10:20:30`
+    pub created: String,
+    pub modified: String,
+}
+
+/// Dataset-level metadata from the MEMBER/DSCRPTR header records
+#[derive(Debug, Clone, Default)]
+pub struct MemberInfo {
+    pub name: String,
+    pub sas_version: String,
+    pub os: String,
+    /// Creation datetime as written, e.g. `16OCT26:10:20:30`
+    pub created: String,
+    pub modified: String,
+    pub label: String,
+    pub dataset_type: String,
+    pub version: XptVersion,
+}
+
+/// Variable metadata matching the expected API (one NAMESTR record)
 #[derive(Debug, Clone)]
 pub struct VarMeta {
     pub name: String,
     pub label: String,
+    pub format_name: String,
+    pub format_len: i16,
+    pub format_decimals: i16,
+    /// nfj: 0 = left justified, 1 = right justified
+    pub format_justification: i16,
+    pub informat_name: String,
+    pub informat_len: i16,
+    pub informat_decimals: i16,
+    /// nlng: storage length in bytes
     pub length: usize,
+    /// npos: byte offset of the value within an observation
     pub position: usize,
+    /// ntype: 1 = numeric, 2 = character
     pub is_char: bool,
+    /// nvar0: variable number (1-based order)
+    pub varnum: i16,
 }
 
-/// Read XPT v5 file from a path
+impl VarMeta {
+    /// Numeric variable stored as a full 8-byte double
+    pub fn numeric(name: impl Into<String>) -> Self {
+        VarMeta::new(name.into(), false, 8)
+    }
+
+    /// Character variable of `length` bytes
+    pub fn character(name: impl Into<String>, length: usize) -> Self {
+        VarMeta::new(name.into(), true, length)
+    }
+
+    fn new(name: String, is_char: bool, length: usize) -> Self {
+        VarMeta {
+            name,
+            label: String::new(),
+            format_name: String::new(),
+            format_len: 0,
+            format_decimals: 0,
+            format_justification: 0,
+            informat_name: String::new(),
+            informat_len: 0,
+            informat_decimals: 0,
+            length,
+            position: 0,
+            is_char,
+            varnum: 0,
+        }
+    }
+}
+
+/// Read XPT v5 or v8 file from a path
+///
+/// All rows are kept in memory; use [`XptReader`] to stream large files.
 pub fn read_xpt_v5<P: AsRef<Path>>(path: P) -> Result<Vec<Dataset>> {
-    let data = fs::read(path)?;
-    read_xpt_v5_from_bytes(&data)
+    let file = File::open(path)?;
+    read_xpt_v5_from_reader(BufReader::new(file))
 }
 
-/// Read XPT v5 from byte slice (for use in Tauri/web contexts)
+/// Read XPT v5 or v8 from byte slice (for use in Tauri/web contexts)
 pub fn read_xpt_v5_from_bytes(data: &[u8]) -> Result<Vec<Dataset>> {
-    // xpt_parser returns a single dataset, convert to our API format
-    let xpt_dataset = xpt_parser::XPTParser::parse(data, None)?;
-    
-    // Convert XPTDataset to Dataset format
-    let vars: Vec<VarMeta> = xpt_dataset.variables.iter()
-        .map(|v| VarMeta {
-            name: v.name.clone(),
-            label: v.label.clone(),
-            length: v.length,
-            position: v.position,
-            is_char: v.var_type == xpt_parser::VariableType::Character,
-        })
-        .collect();
-    
-    let rows: Vec<Vec<Option<String>>> = xpt_dataset.rows.iter()
-        .map(|row| row.values.iter()
-            .map(|v| if v.is_empty() { None } else { Some(v.clone()) })
-            .collect())
-        .collect();
-    
-    Ok(vec![Dataset {
-        name: xpt_dataset.title,
-        vars,
-        rows,
-    }])
+    read_xpt_v5_from_reader(data)
+}
+
+/// Read XPT v5 or v8 file from a path with the given options, returning the
+/// problems recovered from alongside the datasets
+pub fn read_xpt_v5_with<P: AsRef<Path>>(
+    path: P,
+    options: ReadOptions,
+) -> Result<(Vec<Dataset>, Vec<Diagnostic>)> {
+    let file = File::open(path)?;
+    read_xpt_v5_from_reader_with(BufReader::new(file), options)
 }
 
+/// Read XPT v5 or v8 from byte slice with the given options
+pub fn read_xpt_v5_from_bytes_with(
+    data: &[u8],
+    options: ReadOptions,
+) -> Result<(Vec<Dataset>, Vec<Diagnostic>)> {
+    read_xpt_v5_from_reader_with(data, options)
+}
+
+/// Write datasets to a transport file at `path`, one member each
+pub fn write_xpt<P: AsRef<Path>>(path: P, datasets: &[Dataset]) -> Result<()> {
+    write_xpt_with(path, datasets, WriteOptions::default()).map(|_| ())
+}
+
+/// Write datasets to a transport file at `path` with the given options,
+/// returning the problems worked around
+pub fn write_xpt_with<P: AsRef<Path>>(
+    path: P,
+    datasets: &[Dataset],
+    options: WriteOptions,
+) -> Result<Vec<Diagnostic>> {
+    let file = File::create(path)?;
+    write_xpt_to_writer_with(BufWriter::new(file), datasets, options)
+}
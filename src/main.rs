@@ -1,12 +1,29 @@
 use clap::{Parser, Subcommand};
 use anyhow::Result;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::PathBuf;
+use std::process::ExitCode;
 use csv::Writer;
-use xpttools::read_xpt_v5;
+use xpttools::{Columns, MemberReader, NumberFormat, Parallelism, ParseMode, ReadOptions, TextEncoding, Value, XptError, XptReader};
+
+type FileReader = XptReader<BufReader<File>>;
+
+/// Rows per decoding thread converted at a time by xpt2csv
+const CSV_BATCH_ROWS: usize = 16 * 1024;
 
 #[derive(Parser)]
 #[command(name="xpttools", version)]
 struct Cli {
+    /// Reject any deviation from the TS-140 layout instead of recovering
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Encoding of labels and character values: auto, utf-8, windows-1252, latin1, shift_jis, ...
+    #[arg(long, global = true, default_value = "auto")]
+    encoding: TextEncoding,
+    /// Threads decoding observations in xpt2csv (0 = one per core)
+    #[arg(long, global = true, default_value = "1")]
+    threads: usize,
     #[command(subcommand)]
     cmd: Cmd
 }
@@ -22,80 +39,250 @@ enum Cmd {
         file: PathBuf, 
         #[arg(short, long, default_value = "10")] 
         n: usize,
+        /// Start after this many rows
+        #[arg(long, default_value = "0")]
+        skip: usize,
+        #[arg(short, long)]
+        dataset: Option<String>,
+        /// Only show these variables, e.g. USUBJID,PARAMCD,AVAL
+        #[arg(long, value_delimiter = ',')]
+        columns: Vec<String>,
+        /// Numeric rendering: shortest, fixed:N, sig:N or sci:N
+        #[arg(long, default_value = "shortest")]
+        number_format: NumberFormat
+    },
+    /// Display the last n rows of a dataset
+    #[command(name = "xpttail")]
+    XptTail {
+        file: PathBuf,
+        #[arg(short, long, default_value = "10")]
+        n: usize,
         #[arg(short, long)]
-        dataset: Option<String>
+        dataset: Option<String>,
+        /// Only show these variables, e.g. USUBJID,PARAMCD,AVAL
+        #[arg(long, value_delimiter = ',')]
+        columns: Vec<String>,
+        /// Numeric rendering: shortest, fixed:N, sig:N or sci:N
+        #[arg(long, default_value = "shortest")]
+        number_format: NumberFormat
     },
     /// Convert first dataset (or named) to CSV
     #[command(name = "xpt2csv")]
-    Xpt2Csv { file: PathBuf, #[arg(short, long)] dataset: Option<String>, #[arg(short, long)] out: Option<PathBuf> }
+    Xpt2Csv {
+        file: PathBuf,
+        #[arg(short, long)]
+        dataset: Option<String>,
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Start after this many rows
+        #[arg(long, default_value = "0")]
+        skip: usize,
+        /// Only write these variables, e.g. USUBJID,PARAMCD,AVAL
+        #[arg(long, value_delimiter = ',')]
+        columns: Vec<String>,
+        /// Numeric rendering: shortest, fixed:N, sig:N or sci:N
+        #[arg(long, default_value = "shortest")]
+        number_format: NumberFormat
+    }
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    match cli.cmd {
-        Cmd::XptCols { file } => cmd_cols(file),
-        Cmd::XptHead { file, n, dataset } => cmd_head(file, n, dataset),
-        Cmd::Xpt2Csv { file, dataset, out } => cmd_to_csv(file, dataset, out),
+    let options = ReadOptions {
+        mode: if cli.strict { ParseMode::Strict } else { ParseMode::Lenient },
+        encoding: cli.encoding,
+        parallelism: match cli.threads {
+            0 => Parallelism::Available,
+            1 => Parallelism::Sequential,
+            n => Parallelism::Threads(n),
+        },
+        ..Default::default()
+    };
+    let result = match cli.cmd {
+        Cmd::XptCols { file } => cmd_cols(file, options),
+        Cmd::XptHead { file, n, skip, dataset, columns, number_format } => {
+            cmd_head(file, ReadOptions { skip, ..options }, n, dataset, columns, number_format)
+        }
+        Cmd::XptTail { file, n, dataset, columns, number_format } => cmd_tail(file, options, n, dataset, columns, number_format),
+        Cmd::Xpt2Csv { file, dataset, out, skip, columns, number_format } => {
+            cmd_to_csv(file, ReadOptions { skip, ..options }, dataset, out, columns, number_format)
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+/// Process exit status for each kind of failure, so scripts can tell them apart
+fn exit_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<XptError>() {
+        Some(XptError::NotXpt(_)) => 3,
+        Some(XptError::Truncated { .. }) => 4,
+        Some(XptError::BadHeader { .. }) => 5,
+        Some(XptError::InconsistentRowWidth { .. }) => 6,
+        Some(XptError::UnsupportedVersion(_)) => 7,
+        Some(XptError::Io(_)) => 8,
+        Some(XptError::Unwritable { .. }) | None => 1,
     }
 }
 
-fn cmd_cols(file: PathBuf) -> Result<()> {
-    let members = read_xpt_v5(&file)?;
-    for (i, ds) in members.iter().enumerate() {
-        println!("#{}: {}", i+1, ds.name);
-        println!("  Variables ({}):", ds.vars.len());
-        for (idx, v) in ds.vars.iter().enumerate() {
+fn cmd_cols(file: PathBuf, options: ReadOptions) -> Result<()> {
+    let mut reader = XptReader::open_with(&file, options)?;
+    let mut i = 0;
+    while let Some(mut member) = reader.next_member()? {
+        i += 1;
+        let info = member.info();
+        println!("#{}: {}", i, info.name);
+        if !info.label.is_empty() {
+            println!("  Label: {}", info.label);
+        }
+        if !info.dataset_type.is_empty() {
+            println!("  Type: {}", info.dataset_type);
+        }
+        println!("  Created: {}  Modified: {}", info.created, info.modified);
+        if !info.sas_version.is_empty() {
+            println!("  SAS {} on {}", info.sas_version, info.os);
+        }
+        println!("  Observation length: {} bytes", member.row_len());
+        println!("  Variables ({}):", member.vars().len());
+        for v in member.vars() {
             println!("    {:>3}. {:8}  {:>4} bytes @{:>4}  {:5}  label={}",
-                idx + 1, v.name, v.length, v.position, if v.is_char { "CHAR" } else { "NUM" }, v.label);
+                v.varnum, v.name, v.length, v.position, if v.is_char { "CHAR" } else { "NUM" }, v.label);
+            if !v.format_name.is_empty() || v.format_len > 0 {
+                println!("         format={}", sas_format(&v.format_name, v.format_len, v.format_decimals));
+            }
+            if !v.informat_name.is_empty() || v.informat_len > 0 {
+                println!("         informat={}", sas_format(&v.informat_name, v.informat_len, v.informat_decimals));
+            }
         }
-        println!("  Rows: {}", ds.rows.len());
+        println!("  Rows: {}", member.skip_rest()?);
         println!();
     }
+    report_diagnostics(&reader);
     Ok(())
 }
 
-fn cmd_head(file: PathBuf, n: usize, dataset: Option<String>) -> Result<()> {
-    let members = read_xpt_v5(&file)?;
-    if members.is_empty() {
-        anyhow::bail!("No datasets found");
+/// Print the problems the reader recovered from to stderr
+fn report_diagnostics(reader: &FileReader) {
+    for diagnostic in reader.diagnostics() {
+        eprintln!("{}", diagnostic);
     }
-    
-    let ds = if let Some(name) = dataset {
-        members.into_iter()
-            .find(|d| d.name.eq_ignore_ascii_case(&name))
-            .ok_or_else(|| anyhow::anyhow!("Dataset '{}' not found", name))?
-    } else {
-        members.into_iter().next().unwrap()
-    };
-    
-    println!("Dataset: {} (showing first {} rows of {})", ds.name, n.min(ds.rows.len()), ds.rows.len());
+}
+
+/// Render a format reference the way SAS writes it, e.g. `DATE9.` or `8.2`
+fn sas_format(name: &str, len: i16, decimals: i16) -> String {
+    let mut out = name.to_string();
+    if len > 0 {
+        out.push_str(&len.to_string());
+    }
+    out.push('.');
+    if decimals > 0 {
+        out.push_str(&decimals.to_string());
+    }
+    out
+}
+
+/// Restrict the options to the `--columns` list, if one was given
+fn with_columns(options: ReadOptions, columns: &[String]) -> ReadOptions {
+    if columns.is_empty() {
+        return options;
+    }
+    ReadOptions { columns: Columns::names(columns), ..options }
+}
+
+/// Open the named member, or the first one
+fn open_member<'a>(reader: &'a mut FileReader, dataset: Option<&str>) -> Result<MemberReader<'a, BufReader<File>>> {
+    match dataset {
+        Some(name) => reader.find_member(name)?
+            .ok_or_else(|| anyhow::anyhow!("Dataset '{}' not found", name)),
+        None => reader.next_member()?
+            .ok_or_else(|| anyhow::anyhow!("No datasets found")),
+    }
+}
+
+/// Fail if a `--columns` name is not a variable of the member
+fn check_columns(member: &MemberReader<'_, BufReader<File>>, columns: &[String]) -> Result<()> {
+    for name in columns {
+        if !member.vars().iter().any(|v| v.name.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("Column '{}' not found in dataset {}", name, member.info().name);
+        }
+    }
+    Ok(())
+}
+
+fn render_row(row: &[Value], number_format: NumberFormat) -> Vec<String> {
+    row.iter()
+        .map(|v| v.to_string_with(number_format).unwrap_or_default())
+        .collect()
+}
+
+fn cmd_head(file: PathBuf, options: ReadOptions, n: usize, dataset: Option<String>, columns: Vec<String>, number_format: NumberFormat) -> Result<()> {
+    let mut reader = XptReader::open_with(&file, with_columns(options, &columns))?;
+    let mut member = open_member(&mut reader, dataset.as_deref())?;
+    check_columns(&member, &columns)?;
+
+    let first = member.row_index();
+    let mut rows = Vec::with_capacity(n);
+    while rows.len() < n {
+        match member.next_row()? {
+            Some(row) => rows.push(row),
+            None => break,
+        }
+    }
+    // The rest of the member is not read just to count it
+    let total = member.rows_left().map(|left| member.row_index() + left);
+
+    println!("Dataset: {} (showing rows {}{})", member.info().name, row_span(first, rows.len()), of_total(total));
+    print_rows(&member, &rows, number_format);
+    report_diagnostics(&reader);
+    Ok(())
+}
+
+fn cmd_tail(file: PathBuf, options: ReadOptions, n: usize, dataset: Option<String>, columns: Vec<String>, number_format: NumberFormat) -> Result<()> {
+    let mut reader = XptReader::open_with(&file, with_columns(options, &columns))?;
+    let mut member = open_member(&mut reader, dataset.as_deref())?;
+    check_columns(&member, &columns)?;
+
+    let rows = member.tail(n)?;
+    let total = member.row_index();
+
+    println!("Dataset: {} (showing rows {} of {})", member.info().name, row_span(total - rows.len(), rows.len()), total);
+    print_rows(&member, &rows, number_format);
+    report_diagnostics(&reader);
+    Ok(())
+}
+
+/// 1-based row range, e.g. `11-20`
+fn row_span(first: usize, count: usize) -> String {
+    match count {
+        0 => "none".to_string(),
+        _ => format!("{}-{}", first + 1, first + count),
+    }
+}
+
+fn of_total(total: Option<usize>) -> String {
+    total.map(|total| format!(" of {}", total)).unwrap_or_default()
+}
+
+/// Print a tab-separated table of rows under the variable names
+fn print_rows(member: &MemberReader<'_, BufReader<File>>, rows: &[Vec<Value>], number_format: NumberFormat) {
     println!();
-    
-    // Print header
-    let headers: Vec<String> = ds.vars.iter().map(|v| v.name.clone()).collect();
+    let headers: Vec<String> = member.vars().iter().map(|v| v.name.clone()).collect();
     println!("{}", headers.join("\t"));
-    
-    // Print first n rows
-    let rows_to_show = n.min(ds.rows.len());
-    for row in ds.rows.iter().take(rows_to_show) {
-        let values: Vec<String> = row.iter()
-            .map(|opt| opt.as_ref().map(|s| s.clone()).unwrap_or_default())
-            .collect();
-        println!("{}", values.join("\t"));
+    for row in rows {
+        println!("{}", render_row(row, number_format).join("\t"));
     }
-    
-    Ok(())
 }
 
-fn cmd_to_csv(file: PathBuf, dataset: Option<String>, out: Option<PathBuf>) -> Result<()> {
-    let members = read_xpt_v5(&file)?;
-    if members.is_empty() { anyhow::bail!("No datasets found"); }
-    let ds = if let Some(name) = dataset {
-        members.into_iter().find(|d| d.name.eq_ignore_ascii_case(&name))
-            .ok_or_else(|| anyhow::anyhow!("Dataset '{}' not found", name))?
-    } else {
-        members.into_iter().next().unwrap()
-    };
+fn cmd_to_csv(file: PathBuf, options: ReadOptions, dataset: Option<String>, out: Option<PathBuf>, columns: Vec<String>, number_format: NumberFormat) -> Result<()> {
+    let batch_rows = options.parallelism.threads() * CSV_BATCH_ROWS;
+    let mut reader = XptReader::open_with(&file, with_columns(options, &columns))?;
+    let mut member = open_member(&mut reader, dataset.as_deref())?;
+    check_columns(&member, &columns)?;
 
     let mut wtr: Writer<Box<dyn std::io::Write>> = if let Some(path) = out {
         Writer::from_writer(Box::new(std::fs::File::create(path)?) as Box<dyn std::io::Write>)
@@ -104,13 +291,20 @@ fn cmd_to_csv(file: PathBuf, dataset: Option<String>, out: Option<PathBuf>) -> R
     };
 
     // header
-    let headers: Vec<String> = ds.vars.iter().map(|v| v.name.clone()).collect();
+    let headers: Vec<String> = member.vars().iter().map(|v| v.name.clone()).collect();
     wtr.write_record(&headers)?;
 
-    for row in ds.rows {
-        let rec: Vec<String> = row.into_iter().map(|opt| opt.unwrap_or_default()).collect();
-        wtr.write_record(rec)?;
+    // rows are streamed from the file a batch at a time
+    loop {
+        let rows = member.next_batch(batch_rows)?;
+        if rows.is_empty() {
+            break;
+        }
+        for row in &rows {
+            wtr.write_record(render_row(row, number_format))?;
+        }
     }
     wtr.flush()?;
+    report_diagnostics(&reader);
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,416 @@
+use encoding_rs::{Encoding, UTF_8};
+use memmap2::Mmap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::diagnostic::Diagnostic;
+use crate::error::Result;
+use crate::text::{TextDecoder, TextEncoding};
+use crate::xpt::{cell_ranges, decode_cell, decode_column, decode_rows, MemberLayout, XptReader};
+use crate::{LibraryInfo, MemberInfo, Parallelism, ReadOptions, Value, VarMeta};
+
+/// Rows per member sampled to detect the character encoding
+const ENCODING_SAMPLE_ROWS: usize = 1000;
+
+/// Random-access view of a transport file held in memory or memory-mapped
+///
+/// Observations are fixed-width, so once the member headers have been
+/// located any row or cell is found by offset arithmetic and only the
+/// requested cells are decoded. Raw rows are borrowed from the underlying
+/// bytes without copying.
+///
+/// ```no_run
+/// use xpttools::MappedXpt;
+///
+/// let file = MappedXpt::open("LB.xpt")?;
+/// let lb = file.member(0).unwrap();
+/// let aval = lb.var_index("AVAL").unwrap();
+/// for i in 1_000_000..1_000_050 {
+///     println!("{:?}", lb.cell(i, aval));
+/// }
+/// # Ok::<(), xpttools::XptError>(())
+/// ```
+pub struct MappedXpt<B = Mmap> {
+    data: B,
+    library: LibraryInfo,
+    members: Vec<MappedLayout>,
+    encoding: &'static Encoding,
+    parallelism: Parallelism,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Member layout plus the cell offsets derived from it
+struct MappedLayout {
+    layout: MemberLayout,
+    cells: Vec<Range<usize>>,
+}
+
+impl MappedXpt<Mmap> {
+    /// Memory-maps a transport file
+    ///
+    /// The file must not be modified while it is mapped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        MappedXpt::open_with(path, ReadOptions::default())
+    }
+
+    /// Memory-maps a transport file, indexing it with the given options
+    pub fn open_with<P: AsRef<Path>>(path: P, options: ReadOptions) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only; callers must not truncate or rewrite
+        // the file while it is open, as documented above.
+        let data = unsafe { Mmap::map(&file)? };
+        MappedXpt::from_bytes_with(data, options)
+    }
+}
+
+impl<B: AsRef<[u8]>> MappedXpt<B> {
+    /// Indexes a transport file already in memory (e.g. a `Vec<u8>`)
+    ///
+    /// The headers of every member are parsed and the cards of the
+    /// observation sections are scanned in place for the next member
+    /// header, to find where each member ends; no row is copied or decoded.
+    pub fn from_bytes(data: B) -> Result<Self> {
+        MappedXpt::from_bytes_with(data, ReadOptions::default())
+    }
+
+    /// Like [`from_bytes`](Self::from_bytes), with the given options
+    ///
+    /// With [`TextEncoding::Auto`] the encoding is settled from the header
+    /// text or, failing that, from the first rows of each member.
+    /// [`ReadOptions::skip`] and [`ReadOptions::limit`] narrow each member to
+    /// a window of rows, numbered from 0 at its start.
+    pub fn from_bytes_with(data: B, options: ReadOptions) -> Result<Self> {
+        let (skip, limit, parallelism) = (options.skip, options.limit, options.parallelism);
+        let (library, members, encoding, mut diagnostics) = {
+            let mut reader = XptReader::in_memory(data.as_ref(), options)?;
+            let mut members = Vec::new();
+            while let Some(mut layout) = reader.next_member_layout()? {
+                let skipped = skip.min(layout.row_count);
+                layout.obs_offset += skipped * layout.row_len;
+                layout.row_count -= skipped;
+                if let Some(limit) = limit {
+                    layout.row_count = layout.row_count.min(limit);
+                }
+                let cells = cell_ranges(&layout.vars);
+                members.push(MappedLayout { layout, cells });
+            }
+            (
+                reader.library().clone(),
+                members,
+                reader.encoding(),
+                reader.take_diagnostics(),
+            )
+        };
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => sample_encoding(data.as_ref(), &members, &mut diagnostics),
+        };
+        Ok(MappedXpt {
+            data,
+            library,
+            members,
+            encoding,
+            parallelism,
+            diagnostics,
+        })
+    }
+
+    pub fn library(&self) -> &LibraryInfo {
+        &self.library
+    }
+
+    /// Encoding used for labels and character values
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// Problems recovered from while indexing the file
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Number of members (datasets) in the file
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Member at `index`, in file order
+    pub fn member(&self, index: usize) -> Option<MappedMember<'_>> {
+        let mapped = self.members.get(index)?;
+        let layout = &mapped.layout;
+        let start = layout.obs_offset;
+        let end = start + layout.row_count * layout.row_len;
+        Some(MappedMember {
+            layout,
+            cells: &mapped.cells,
+            obs: &self.data.as_ref()[start..end],
+            encoding: self.encoding,
+            parallelism: self.parallelism,
+        })
+    }
+
+    /// First member named `name` (case-insensitive)
+    pub fn find_member(&self, name: &str) -> Option<MappedMember<'_>> {
+        let index = self
+            .members
+            .iter()
+            .position(|m| m.layout.info.name.eq_ignore_ascii_case(name))?;
+        self.member(index)
+    }
+
+    /// All members, in file order
+    pub fn members(&self) -> impl Iterator<Item = MappedMember<'_>> {
+        (0..self.members.len()).filter_map(move |index| self.member(index))
+    }
+}
+
+/// Borrowed view of one member of a [`MappedXpt`]
+#[derive(Clone, Copy)]
+pub struct MappedMember<'a> {
+    layout: &'a MemberLayout,
+    cells: &'a [Range<usize>],
+    /// Observation bytes of this member, `row_count * row_len` long
+    obs: &'a [u8],
+    encoding: &'static Encoding,
+    parallelism: Parallelism,
+}
+
+impl<'a> MappedMember<'a> {
+    pub fn info(&self) -> &'a MemberInfo {
+        &self.layout.info
+    }
+
+    pub fn vars(&self) -> &'a [VarMeta] {
+        &self.layout.vars
+    }
+
+    /// Index of the variable named `name` (case-insensitive)
+    pub fn var_index(&self, name: &str) -> Option<usize> {
+        self.layout
+            .vars
+            .iter()
+            .position(|v| v.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.layout.row_count
+    }
+
+    /// Width of one observation in bytes
+    pub fn row_len(&self) -> usize {
+        self.layout.row_len
+    }
+
+    /// Undecoded bytes of row `index`
+    pub fn raw_row(&self, index: usize) -> Option<&'a [u8]> {
+        self.raw_rows(index..index.checked_add(1)?)
+    }
+
+    /// Undecoded bytes of a contiguous range of rows
+    pub fn raw_rows(&self, rows: Range<usize>) -> Option<&'a [u8]> {
+        if rows.start > rows.end || rows.end > self.layout.row_count {
+            return None;
+        }
+        let row_len = self.layout.row_len;
+        Some(&self.obs[rows.start * row_len..rows.end * row_len])
+    }
+
+    /// Undecoded bytes of one cell
+    pub fn raw_cell(&self, row: usize, var: usize) -> Option<&'a [u8]> {
+        let range = self.cells.get(var)?.clone();
+        Some(&self.raw_row(row)?[range])
+    }
+
+    /// Decodes row `index`
+    pub fn row(&self, index: usize) -> Option<Vec<Value>> {
+        let raw = self.raw_row(index)?;
+        let mut text = self.text_decoder();
+        Some(
+            self.layout
+                .vars
+                .iter()
+                .zip(self.cells)
+                .map(|(var, range)| decode_cell(&raw[range.clone()], var, &mut text))
+                .collect(),
+        )
+    }
+
+    /// Decodes a single cell
+    pub fn cell(&self, row: usize, var: usize) -> Option<Value> {
+        let raw = self.raw_cell(row, var)?;
+        Some(decode_cell(raw, &self.layout.vars[var], &mut self.text_decoder()))
+    }
+
+    /// Decodes a range of rows at once, on several threads as set by
+    /// [`ReadOptions::parallelism`]
+    pub fn decode_rows(&self, rows: Range<usize>) -> Option<Vec<Vec<Value>>> {
+        let raw = self.raw_rows(rows)?;
+        let threads = self.parallelism.threads();
+        let (rows, _) = decode_rows(raw, self.layout.row_len, &self.layout.vars, &mut self.text_decoder(), threads);
+        Some(rows)
+    }
+
+    /// Decodes every value of one variable, on several threads as set by
+    /// [`ReadOptions::parallelism`]
+    pub fn column(&self, var: usize) -> Option<Vec<Value>> {
+        let var = self.layout.vars.get(var)?;
+        let threads = self.parallelism.threads();
+        Some(decode_column(self.obs, self.layout.row_len, var, &mut self.text_decoder(), threads))
+    }
+
+    fn text_decoder(&self) -> TextDecoder {
+        TextDecoder::new(TextEncoding::Fixed(self.encoding))
+    }
+
+    /// Lazily decodes a range of rows
+    pub fn rows(&self, rows: Range<usize>) -> impl Iterator<Item = Vec<Value>> + 'a {
+        let member = *self;
+        let end = rows.end.min(self.layout.row_count);
+        (rows.start..end).filter_map(move |index| member.row(index))
+    }
+}
+
+/// Detects the encoding from the character cells of the first rows of each
+/// member, defaulting to UTF-8 when they are all ASCII
+fn sample_encoding(
+    data: &[u8],
+    members: &[MappedLayout],
+    diagnostics: &mut Vec<Diagnostic>,
+) -> &'static Encoding {
+    let mut text = TextDecoder::new(TextEncoding::Auto);
+    for mapped in members {
+        let layout = &mapped.layout;
+        for row in 0..layout.row_count.min(ENCODING_SAMPLE_ROWS) {
+            let row_offset = layout.obs_offset + row * layout.row_len;
+            for (var, range) in layout.vars.iter().zip(&mapped.cells) {
+                if !var.is_char {
+                    continue;
+                }
+                let cell = &data[row_offset + range.start..row_offset + range.end];
+                text.decode(cell);
+                if let Some(encoding) = text.encoding() {
+                    for (severity, message) in text.take_notes() {
+                        diagnostics.push(Diagnostic {
+                            severity,
+                            offset: (row_offset + range.start) as u64,
+                            member: Some(layout.info.name.clone()),
+                            message,
+                        });
+                    }
+                    return encoding;
+                }
+            }
+        }
+    }
+    UTF_8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::write_xpt_to_writer;
+    use crate::{read_xpt_v5_from_reader_with, Dataset, MissingKind};
+
+    /// Two members whose observations end with blank rows, the second one
+    /// of character values only
+    fn sample() -> Vec<u8> {
+        let vars = vec![VarMeta::character("ID", 4), VarMeta::numeric("X")];
+        let rows = (0..1000)
+            .map(|i| vec![Value::Character(format!("r{}", i)), Value::Numeric(i as f64)])
+            .collect();
+        let text = (0..998)
+            .map(|i: usize| if i >= 995 { String::new() } else { i.to_string() })
+            .map(|c| vec![Value::Character(c)])
+            .collect();
+        let datasets = [
+            Dataset::new("NUM", vars, rows),
+            Dataset::new("CHAR", vec![VarMeta::character("C", 8)], text),
+        ];
+        let mut data = Vec::new();
+        write_xpt_to_writer(&mut data, &datasets).unwrap();
+        data
+    }
+
+    #[test]
+    fn index_matches_a_streaming_read() {
+        let data = sample();
+        let windows = [(0, None), (10, Some(5)), (990, None), (997, Some(100)), (5000, None)];
+        for (skip, limit) in windows {
+            let options = ReadOptions {
+                skip,
+                limit,
+                ..ReadOptions::default()
+            };
+            let (datasets, diagnostics) =
+                read_xpt_v5_from_reader_with(data.as_slice(), options.clone()).unwrap();
+            let mapped = MappedXpt::from_bytes_with(data.as_slice(), options).unwrap();
+            assert_eq!(mapped.len(), datasets.len());
+            for (member, dataset) in mapped.members().zip(&datasets) {
+                assert_eq!(member.decode_rows(0..member.row_count()).unwrap(), dataset.rows);
+            }
+            assert_eq!(mapped.diagnostics(), diagnostics);
+        }
+    }
+
+    #[test]
+    fn numeric_columns_match_the_rows() {
+        let mut short = VarMeta::numeric("SHORT");
+        short.length = 3;
+        let vars = vec![VarMeta::numeric("X"), VarMeta::character("C", 3), short];
+        let rows = (0..5000)
+            .map(|i| {
+                let x = match i % 4 {
+                    0 => Value::Missing(MissingKind::Dot),
+                    1 => Value::Missing(MissingKind::Letter(b'A' + (i % 26) as u8)),
+                    2 => Value::Missing(MissingKind::Underscore),
+                    _ => Value::Numeric(i as f64 / 3.0),
+                };
+                vec![x, Value::Character("c".to_string()), Value::Numeric(i as f64)]
+            })
+            .collect();
+        let mut data = Vec::new();
+        write_xpt_to_writer(&mut data, &[Dataset::new("T", vars, rows)]).unwrap();
+
+        for parallelism in [Parallelism::Sequential, Parallelism::Threads(4)] {
+            let options = ReadOptions {
+                parallelism,
+                ..ReadOptions::default()
+            };
+            let mapped = MappedXpt::from_bytes_with(data.as_slice(), options).unwrap();
+            let member = mapped.member(0).unwrap();
+            for var in 0..3 {
+                let expected: Vec<Value> = member.rows(0..5000).map(|row| row[var].clone()).collect();
+                assert_eq!(member.column(var).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_lookups_return_none() {
+        let data = sample();
+        let mapped = MappedXpt::from_bytes(data.as_slice()).unwrap();
+        let member = mapped.member(0).unwrap();
+        let rows = member.row_count();
+        assert_eq!(rows, 1000);
+        assert!(member.row(rows - 1).is_some());
+        assert_eq!(member.cell(rows - 1, 1), Some(Value::Numeric(999.0)));
+
+        for row in [rows, usize::MAX] {
+            assert!(member.raw_row(row).is_none());
+            assert!(member.row(row).is_none());
+            assert!(member.cell(row, 0).is_none());
+            assert!(member.raw_cell(row, 0).is_none());
+        }
+        assert!(member.cell(0, 2).is_none());
+        assert!(member.column(2).is_none());
+        assert!(member.raw_rows(rows..rows + 1).is_none());
+        assert!(member.decode_rows(0..rows + 1).is_none());
+        assert_eq!(member.rows(rows - 2..usize::MAX).count(), 2);
+        assert!(mapped.member(2).is_none());
+    }
+}
@@ -0,0 +1,32 @@
+use std::ops::Range;
+use std::panic;
+use std::thread;
+
+/// Fewest rows worth handing to a thread of their own
+const MIN_CHUNK_ROWS: usize = 1024;
+
+/// Splits `0..count` into contiguous chunks, one per thread, and runs `work`
+/// on each. Results come back in chunk order whatever the scheduling, so
+/// the output is the same as running `work(0..count)` on one thread.
+pub(crate) fn map_chunks<T, F>(count: usize, threads: usize, work: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(Range<usize>) -> T + Sync,
+{
+    let threads = threads.min(count / MIN_CHUNK_ROWS).max(1);
+    if threads == 1 {
+        return vec![work(0..count)];
+    }
+    let chunk = count.div_ceil(threads);
+    let work = &work;
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..count)
+            .step_by(chunk)
+            .map(|start| scope.spawn(move || work(start..count.min(start + chunk))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
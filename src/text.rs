@@ -0,0 +1,166 @@
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::diagnostic::Severity;
+
+/// Character encoding of labels and character values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    /// Decided from the first non-ASCII text in the file: UTF-8 if it is
+    /// valid UTF-8, Windows-1252 otherwise
+    #[default]
+    Auto,
+    /// A fixed encoding, e.g. `encoding_rs::SHIFT_JIS`
+    Fixed(&'static Encoding),
+}
+
+impl FromStr for TextEncoding {
+    type Err = String;
+
+    /// Parses `auto` or an encoding label such as `utf-8`, `windows-1252`,
+    /// `latin1`, `iso-8859-15` or `shift_jis`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("auto") {
+            return Ok(TextEncoding::Auto);
+        }
+        Encoding::for_label(s.trim().as_bytes())
+            .map(TextEncoding::Fixed)
+            .ok_or_else(|| format!("Unknown encoding '{}'", s))
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextEncoding::Auto => write!(f, "auto"),
+            TextEncoding::Fixed(encoding) => write!(f, "{}", encoding.name()),
+        }
+    }
+}
+
+/// Decodes text fields, settling the encoding on first use when it is
+/// auto-detected
+pub(crate) struct TextDecoder {
+    /// `None` while auto-detection has only seen ASCII
+    encoding: Option<&'static Encoding>,
+    /// Set once a malformed value has been reported
+    warned: bool,
+    /// Notes for the reader to turn into diagnostics
+    notes: Vec<(Severity, String)>,
+}
+
+impl TextDecoder {
+    pub fn new(encoding: TextEncoding) -> Self {
+        TextDecoder {
+            encoding: match encoding {
+                TextEncoding::Auto => None,
+                TextEncoding::Fixed(encoding) => Some(encoding),
+            },
+            warned: false,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Encoding in use, `None` if auto-detection has not seen any non-ASCII
+    /// text yet
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.encoding
+    }
+
+    /// Decodes `bytes`, dropping trailing blanks and NULs
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        let end = bytes
+            .iter()
+            .rposition(|&b| b != b' ' && b != 0)
+            .map_or(0, |last| last + 1);
+        let bytes = &bytes[..end];
+        let encoding = match self.settle(bytes) {
+            Some(encoding) => encoding,
+            // ASCII reads the same in every supported encoding
+            None => return bytes.iter().map(|&b| b as char).collect(),
+        };
+        let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+        if had_errors && !self.warned {
+            self.warned = true;
+            self.notes.push((
+                Severity::Warning,
+                format!(
+                    "text is not valid {}; malformed bytes were replaced",
+                    encoding.name()
+                ),
+            ));
+        }
+        text.trim_end_matches(|c: char| c.is_whitespace() || c == '\0')
+            .to_string()
+    }
+
+    /// Encoding to decode `bytes` with, detecting it if they are the first
+    /// non-ASCII text seen; `None` if they are ASCII
+    pub fn settle(&mut self, bytes: &[u8]) -> Option<&'static Encoding> {
+        if bytes.is_ascii() {
+            return None;
+        }
+        if self.encoding.is_none() {
+            let encoding = detect(bytes);
+            self.encoding = Some(encoding);
+            self.notes.push((
+                Severity::Info,
+                format!("character encoding detected as {}", encoding.name()),
+            ));
+        }
+        self.encoding
+    }
+
+    /// Decoder for another thread, starting from the encoding settled so far
+    pub fn fork(&self) -> TextDecoder {
+        TextDecoder {
+            encoding: self.encoding,
+            warned: self.warned,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Takes back the state and notes of a decoder made by [`fork`](Self::fork),
+    /// keeping a single malformed-text warning
+    pub fn join(&mut self, fork: TextDecoder) {
+        if self.encoding.is_none() {
+            self.encoding = fork.encoding;
+        }
+        for (severity, message) in fork.notes {
+            if self.keep_note(severity) {
+                self.notes.push((severity, message));
+            }
+        }
+    }
+
+    /// Whether a note of a fork, taken before [`join`](Self::join), is to be
+    /// reported: only the first malformed-text warning is
+    pub fn keep_note(&mut self, severity: Severity) -> bool {
+        if severity == Severity::Warning {
+            if self.warned {
+                return false;
+            }
+            self.warned = true;
+        }
+        true
+    }
+
+    /// Takes the notes produced since the last call
+    pub fn take_notes(&mut self) -> Vec<(Severity, String)> {
+        std::mem::take(&mut self.notes)
+    }
+}
+
+/// Guesses the encoding of non-ASCII text
+///
+/// Valid multi-byte UTF-8 is very unlikely to occur by chance in single-byte
+/// encodings, so anything that is not UTF-8 is taken as Windows-1252, the
+/// superset of Latin-1 that SAS uses on Windows.
+fn detect(bytes: &[u8]) -> &'static Encoding {
+    if std::str::from_utf8(bytes).is_ok() {
+        UTF_8
+    } else {
+        WINDOWS_1252
+    }
+}
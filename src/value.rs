@@ -0,0 +1,163 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ibm370::IbmMissing;
+
+/// A single decoded cell of a dataset
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Numeric value converted from IBM 360 floating point
+    Numeric(f64),
+    /// Character value with trailing blanks removed
+    Character(String),
+    /// Missing numeric value
+    Missing(MissingKind),
+}
+
+/// Kind of a missing numeric value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingKind {
+    /// Standard missing (`.`)
+    Dot,
+    /// Special missing (`._`)
+    Underscore,
+    /// Special missing (`.A`-`.Z`), holding the ASCII letter
+    Letter(u8),
+}
+
+impl Value {
+    /// Builds a numeric cell from the result of `ibm64_to_f64`
+    pub fn from_ibm(decoded: (Option<f64>, IbmMissing)) -> Value {
+        match decoded {
+            (_, IbmMissing::Underscore) => Value::Missing(MissingKind::Underscore),
+            (_, IbmMissing::Letter(letter)) => Value::Missing(MissingKind::Letter(letter)),
+            (Some(value), IbmMissing::None) => Value::Numeric(value),
+            _ => Value::Missing(MissingKind::Dot),
+        }
+    }
+
+    /// Numeric value, if this is a non-missing numeric cell
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Numeric(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Text, if this is a character cell
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Character(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        matches!(self, Value::Missing(_))
+    }
+
+    /// String view of the cell with the default [`NumberFormat`]
+    pub fn to_string_opt(&self) -> Option<String> {
+        self.to_string_with(NumberFormat::default())
+    }
+
+    /// String view of the cell: numbers rendered with `format`, special
+    /// missing values as `._` or `.A`-`.Z`, `None` for standard missing
+    /// values and empty character values
+    pub fn to_string_with(&self, format: NumberFormat) -> Option<String> {
+        match self {
+            Value::Numeric(value) => Some(format.format(*value)),
+            Value::Character(text) if !text.is_empty() => Some(text.clone()),
+            Value::Missing(kind) if kind.is_special() => Some(kind.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl MissingKind {
+    /// True for `._` and `.A`-`.Z`
+    pub fn is_special(&self) -> bool {
+        !matches!(self, MissingKind::Dot)
+    }
+}
+
+impl From<MissingKind> for IbmMissing {
+    fn from(kind: MissingKind) -> Self {
+        match kind {
+            MissingKind::Dot => IbmMissing::Dot,
+            MissingKind::Underscore => IbmMissing::Underscore,
+            MissingKind::Letter(letter) => IbmMissing::Letter(letter),
+        }
+    }
+}
+
+impl fmt::Display for MissingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingKind::Dot => write!(f, "."),
+            MissingKind::Underscore => write!(f, "._"),
+            MissingKind::Letter(letter) => write!(f, ".{}", *letter as char),
+        }
+    }
+}
+
+/// Textual rendering of numeric values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumberFormat {
+    /// Shortest text that parses back to the exact same f64
+    #[default]
+    Shortest,
+    /// Fixed number of decimals, e.g. `Fixed(2)` renders `3.14`
+    Fixed(usize),
+    /// Rounded to a number of significant digits, e.g. `Significant(3)` renders `0.00123`
+    Significant(usize),
+    /// Scientific notation with a number of mantissa decimals, e.g. `1.23e-9`
+    Scientific(usize),
+}
+
+impl NumberFormat {
+    pub fn format(&self, value: f64) -> String {
+        // IBM floats have no negative zero worth showing
+        let value = if value == 0.0 { 0.0 } else { value };
+        match *self {
+            NumberFormat::Shortest => value.to_string(),
+            NumberFormat::Fixed(decimals) => format!("{:.*}", decimals, value),
+            NumberFormat::Significant(digits) => {
+                let digits = digits.max(1);
+                let rounded: f64 = format!("{:.*e}", digits - 1, value)
+                    .parse()
+                    .unwrap_or(value);
+                rounded.to_string()
+            }
+            NumberFormat::Scientific(decimals) => format!("{:.*e}", decimals, value),
+        }
+    }
+}
+
+impl FromStr for NumberFormat {
+    type Err = String;
+
+    /// Parses `shortest`, `fixed:N`, `sig:N` or `sci:N`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, digits) = match s.split_once(':') {
+            Some((mode, digits)) => {
+                let digits = digits
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid digit count in number format '{}'", s))?;
+                (mode.trim(), Some(digits))
+            }
+            None => (s.trim(), None),
+        };
+        match (mode.to_ascii_lowercase().as_str(), digits) {
+            ("shortest", None) => Ok(NumberFormat::Shortest),
+            ("fixed", Some(n)) => Ok(NumberFormat::Fixed(n)),
+            ("sig", Some(n)) => Ok(NumberFormat::Significant(n)),
+            ("sci", Some(n)) => Ok(NumberFormat::Scientific(n)),
+            _ => Err(format!(
+                "Unknown number format '{}' (expected shortest, fixed:N, sig:N or sci:N)",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,1224 @@
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::card::{Card, CardWriter, CARD};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::{Result, XptError};
+use crate::ibm370::{f64_to_ibm, missing_to_ibm64, IbmEncodeError, IbmMissing};
+use crate::{Dataset, LibraryInfo, MemberInfo, Value, VarMeta, WriteOptions, XptVersion};
+
+/// Length of the NAMESTR records written
+const NAME_STRING_LENGTH: usize = 140;
+/// Room for a name in a NAMESTR record; V8 records hold the full name too
+const SHORT_NAME_LENGTH: usize = 8;
+/// Room for a label in a NAMESTR record; V8 files hold longer ones in
+/// LABELV8/LABELV9 records
+const SHORT_LABEL_LENGTH: usize = 40;
+/// Most variables the NAMESTR header can count
+const MAX_VARIABLES: usize = 9999;
+/// SAS release written when the metadata does not name one
+const DEFAULT_SAS_VERSION: &str = "9.4";
+
+/// Metadata limits of a transport version, in characters for names and
+/// bytes for labels
+struct Limits {
+    name: usize,
+    label: usize,
+    member_label: usize,
+    format: usize,
+    character: usize,
+}
+
+impl Limits {
+    fn of(version: XptVersion) -> Limits {
+        match version {
+            XptVersion::V5 => Limits {
+                name: 8,
+                label: 40,
+                member_label: 40,
+                format: 8,
+                character: 200,
+            },
+            XptVersion::V8 => Limits {
+                name: 32,
+                label: 256,
+                member_label: 40,
+                format: 32,
+                character: 32767,
+            },
+        }
+    }
+}
+
+/// Header record names of a transport version
+struct Headers {
+    library: &'static str,
+    member: &'static str,
+    descriptor: &'static str,
+    namestr: &'static str,
+    obs: &'static str,
+}
+
+impl Headers {
+    fn of(version: XptVersion) -> Headers {
+        match version {
+            XptVersion::V5 => Headers {
+                library: "LIBRARY",
+                member: "MEMBER",
+                descriptor: "DSCRPTR",
+                namestr: "NAMESTR",
+                obs: "OBS",
+            },
+            XptVersion::V8 => Headers {
+                library: "LIBV8",
+                member: "MEMBV8",
+                descriptor: "DSCPTV8",
+                namestr: "NAMSTV8",
+                obs: "OBSV8",
+            },
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+/// Write datasets as a transport file to any writer, one member each
+pub fn write_xpt_to_writer<W: Write>(out: W, datasets: &[Dataset]) -> Result<()> {
+    write_xpt_to_writer_with(out, datasets, WriteOptions::default()).map(|_| ())
+}
+
+/// Write datasets as a transport file to any writer with the given options,
+/// returning the problems worked around
+///
+/// The file is V5 or V8 as set by [`WriteOptions::version`]. The library
+/// header is taken from the first dataset. Variables are laid
+/// out one after another in the order given, so their `position` and
+/// `varnum` are recomputed.
+pub fn write_xpt_to_writer_with<W: Write>(
+    out: W,
+    datasets: &[Dataset],
+    options: WriteOptions,
+) -> Result<Vec<Diagnostic>> {
+    let first = datasets.first().ok_or_else(|| XptError::Unwritable {
+        member: None,
+        message: "no datasets to write".to_string(),
+    })?;
+    let mut writer = XptWriter::with_options(out, &first.library, options)?;
+    for dataset in datasets {
+        let mut info = dataset.member.clone();
+        if !dataset.name.is_empty() {
+            info.name = dataset.name.clone();
+        }
+        let mut member = writer.start_member_with_count(&info, &dataset.vars, dataset.rows.len())?;
+        member.write_rows(&dataset.rows)?;
+    }
+    let diagnostics = writer.take_diagnostics();
+    writer.finish()?;
+    Ok(diagnostics)
+}
+
+/// Streaming writer for SAS XPORT Version 5 and 8 transport files
+///
+/// Members are written one after another with
+/// [`start_member`](Self::start_member); each returned [`MemberWriter`]
+/// encodes observations as they are given, so memory use does not depend
+/// on the number of rows.
+///
+/// ```no_run
+/// use xpttools::{LibraryInfo, MemberInfo, Value, VarMeta, XptWriter};
+///
+/// let mut writer = XptWriter::create("LB.xpt", &LibraryInfo::default())?;
+/// let info = MemberInfo { name: "LB".to_string(), ..MemberInfo::default() };
+/// let vars = [VarMeta::character("USUBJID", 20), VarMeta::numeric("AVAL")];
+/// let mut member = writer.start_member(&info, &vars)?;
+/// for i in 0..1_000_000 {
+///     member.write_row(&[Value::Character(format!("S{}", i % 500)), Value::Numeric(i as f64)])?;
+/// }
+/// writer.finish()?;
+/// # Ok::<(), xpttools::XptError>(())
+/// ```
+///
+/// [`finish`](Self::finish) must be called once the last row is written:
+/// it pads the last card and, for V8 members whose row count was not given
+/// up front, fills in the count.
+pub struct XptWriter<W> {
+    cards: CardWriter<W>,
+    options: WriteOptions,
+    /// Header datetime used where the metadata has none
+    now: String,
+    /// Names of the members written so far
+    members: Vec<String>,
+    /// Member being written, for error reports
+    member_name: Option<String>,
+    /// Row count bookkeeping of the member whose rows are being written
+    current: Option<MemberState>,
+    vars: Vec<VarMeta>,
+    /// Observation being encoded
+    row: Vec<u8>,
+    rows_written: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Row count of a member, as declared and as still to be written
+struct MemberState {
+    /// Rows promised by [`XptWriter::start_member_with_count`]
+    declared: Option<usize>,
+    /// Offset of the row count in the OBSV8 header, filled in when the
+    /// member ends
+    count_offset: Option<u64>,
+}
+
+impl XptWriter<BufWriter<File>> {
+    /// Creates a transport file at `path` and writes its LIBRARY header
+    pub fn create<P: AsRef<Path>>(path: P, library: &LibraryInfo) -> Result<Self> {
+        XptWriter::create_with(path, library, WriteOptions::default())
+    }
+
+    /// Creates a transport file at `path` with the given options
+    pub fn create_with<P: AsRef<Path>>(
+        path: P,
+        library: &LibraryInfo,
+        options: WriteOptions,
+    ) -> Result<Self> {
+        let file = File::create(path)?;
+        XptWriter::seekable(BufWriter::new(file), library, options)
+    }
+}
+
+impl<W: Write + Seek> XptWriter<W> {
+    /// Like [`with_options`](Self::with_options), for output that can seek:
+    /// V8 members then need no row count up front, as it is written into
+    /// the OBSV8 header once the member ends.
+    pub fn seekable(inner: W, library: &LibraryInfo, options: WriteOptions) -> Result<Self> {
+        XptWriter::from_cards(CardWriter::seekable(inner), library, options)
+    }
+}
+
+impl<W: Write> XptWriter<W> {
+    /// Wraps a writer positioned where the transport file should start and
+    /// writes the LIBRARY header from `library`
+    pub fn new(inner: W, library: &LibraryInfo) -> Result<Self> {
+        XptWriter::with_options(inner, library, WriteOptions::default())
+    }
+
+    /// Like [`new`](Self::new), with the given options
+    pub fn with_options(inner: W, library: &LibraryInfo, options: WriteOptions) -> Result<Self> {
+        XptWriter::from_cards(CardWriter::new(inner), library, options)
+    }
+
+    fn from_cards(
+        cards: CardWriter<W>,
+        library: &LibraryInfo,
+        options: WriteOptions,
+    ) -> Result<Self> {
+        let mut writer = XptWriter {
+            cards,
+            options,
+            now: sas_datetime(SystemTime::now()),
+            members: Vec::new(),
+            member_name: None,
+            current: None,
+            vars: Vec::new(),
+            row: Vec::new(),
+            rows_written: 0,
+            diagnostics: Vec::new(),
+        };
+        writer.write_library(library)?;
+        Ok(writer)
+    }
+
+    /// Problems worked around so far, in file order
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Takes the problems worked around so far, leaving the list empty
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Ends the current member and starts the next, writing its headers
+    ///
+    /// V8 headers hold the row count, so on output that cannot seek V8
+    /// members must be started with
+    /// [`start_member_with_count`](Self::start_member_with_count).
+    pub fn start_member(
+        &mut self,
+        info: &MemberInfo,
+        vars: &[VarMeta],
+    ) -> Result<MemberWriter<'_, W>> {
+        self.begin_member(info, vars, None)
+    }
+
+    /// Like [`start_member`](Self::start_member), for a member that will
+    /// have exactly `row_count` observations; ending it with a different
+    /// number is an error
+    pub fn start_member_with_count(
+        &mut self,
+        info: &MemberInfo,
+        vars: &[VarMeta],
+        row_count: usize,
+    ) -> Result<MemberWriter<'_, W>> {
+        self.begin_member(info, vars, Some(row_count))
+    }
+
+    /// Ends the last member, pads the last card and flushes the output,
+    /// returning the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.end_member()?;
+        if self.members.is_empty() {
+            return Err(self.unwritable("no members written".to_string()));
+        }
+        self.cards.finish()?;
+        Ok(self.cards.into_inner())
+    }
+
+    fn begin_member(
+        &mut self,
+        info: &MemberInfo,
+        vars: &[VarMeta],
+        declared: Option<usize>,
+    ) -> Result<MemberWriter<'_, W>> {
+        self.end_member()?;
+        self.member_name = Some(info.name.clone());
+        if self.options.version == XptVersion::V8 && declared.is_none() && !self.cards.can_seek() {
+            return Err(self.unwritable(
+                "V8 members need their row count up front when the output cannot seek".to_string(),
+            ));
+        }
+        let count_offset = self.write_member_headers(info, vars, declared)?;
+        self.current = Some(MemberState { declared, count_offset });
+        Ok(MemberWriter { writer: self })
+    }
+
+    /// Pads the last card of the current member and checks or fills in its
+    /// row count
+    fn end_member(&mut self) -> Result<()> {
+        let state = match self.current.take() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        self.cards.finish_card()?;
+        if let Some(declared) = state.declared {
+            if declared != self.rows_written {
+                return Err(self.unwritable(format!(
+                    "{} observations declared but {} written",
+                    declared, self.rows_written
+                )));
+            }
+        }
+        if let Some(offset) = state.count_offset {
+            self.cards.overwrite(offset, format!("{:015}", self.rows_written).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the LIBRARY header and its two data cards
+    ///
+    /// ```text
+    /// SAS     SAS     SASLIB  9.4     LINUX                           16OCT26:10:20:30
+    /// 16OCT26:10:20:30
+    /// ```
+    fn write_library(&mut self, library: &LibraryInfo) -> Result<()> {
+        let mut first = blank_card();
+        put_ascii(&mut first[0..8], "SAS", "library", None)?;
+        put_ascii(&mut first[8..16], "SAS", "library", None)?;
+        put_ascii(&mut first[16..24], "SASLIB", "library", None)?;
+        self.put_origin(&mut first[24..40], &library.sas_version, &library.os, None)?;
+        self.put_datetime(&mut first[64..80], &library.created, None)?;
+        let mut second = blank_card();
+        self.put_datetime(&mut second[0..16], &library.modified, None)?;
+
+        let headers = Headers::of(self.options.version);
+        self.cards.write_card(&header_card(headers.library, ""))?;
+        self.cards.write_card(&first)?;
+        self.cards.write_card(&second)?;
+        Ok(())
+    }
+
+    /// Writes the headers and NAMESTR records of the next member. Returns
+    /// where its row count is to be filled in, for V8 members started
+    /// without one.
+    fn write_member_headers(
+        &mut self,
+        info: &MemberInfo,
+        vars: &[VarMeta],
+        row_count: Option<usize>,
+    ) -> Result<Option<u64>> {
+        let version = self.options.version;
+        let name = self.fit_name(&info.name, "member name", |l| l.name)?;
+        self.check_member_name(&name)?;
+        self.member_name = Some(name.clone());
+        if vars.is_empty() {
+            return Err(self.unwritable("member has no variables".to_string()));
+        }
+        if vars.len() > MAX_VARIABLES {
+            return Err(self.unwritable(format!(
+                "{} variables exceed the limit of {}",
+                vars.len(),
+                MAX_VARIABLES
+            )));
+        }
+
+        // In V8 files the member name takes 32 characters
+        let origin = match version {
+            XptVersion::V5 => 24,
+            XptVersion::V8 => 48,
+        };
+        let label = self.fit_text(&info.label, "member label", |l| l.member_label)?;
+        let member = self.member_name.as_deref();
+        let mut first = blank_card();
+        put_ascii(&mut first[0..8], "SAS", "member", member)?;
+        put_ascii(&mut first[8..origin - 8], &name, "member name", member)?;
+        put_ascii(&mut first[origin - 8..origin], "SASDATA", "member", member)?;
+        self.put_origin(&mut first[origin..origin + 16], &info.sas_version, &info.os, member)?;
+        self.put_datetime(&mut first[64..80], &info.created, member)?;
+        let mut second = blank_card();
+        self.put_datetime(&mut second[0..16], &info.modified, member)?;
+        pad_copy(&mut second[32..72], &encode_prefix(self.options.encoding, &label, SHORT_LABEL_LENGTH));
+        put_ascii(&mut second[72..80], &info.dataset_type, "dataset type", member)?;
+
+        // Values are laid out one after another, in the order given
+        let mut laid_out: Vec<VarMeta> = Vec::with_capacity(vars.len());
+        let mut position = 0;
+        for (index, var) in vars.iter().enumerate() {
+            let var = VarMeta {
+                name: self.fit_name(&var.name, "variable name", |l| l.name)?,
+                label: self.fit_text(&var.label, "variable label", |l| l.label)?,
+                format_name: self.fit_name(&var.format_name, "format name", |l| l.format)?,
+                informat_name: self.fit_name(&var.informat_name, "informat name", |l| l.format)?,
+                position,
+                varnum: (index + 1) as i16,
+                ..var.clone()
+            };
+            self.check_var(&var, &laid_out)?;
+            position += var.length;
+            laid_out.push(var);
+        }
+        let mut names = Vec::with_capacity(vars.len() * NAME_STRING_LENGTH);
+        for var in &laid_out {
+            names.extend_from_slice(&self.name_string(var)?);
+        }
+
+        let headers = Headers::of(version);
+        self.cards.write_card(&header_card(
+            headers.member,
+            &format!("000000000000000001600000000{:03}", NAME_STRING_LENGTH),
+        ))?;
+        self.cards.write_card(&header_card(headers.descriptor, ""))?;
+        self.cards.write_card(&first)?;
+        self.cards.write_card(&second)?;
+        self.cards.write_card(&header_card(
+            headers.namestr,
+            &format!("000000{:04}00000000000000000000", laid_out.len()),
+        ))?;
+        self.cards.write_record(&names)?;
+        if version == XptVersion::V8 {
+            self.write_long_labels(&laid_out)?;
+        }
+        self.cards.finish_card()?;
+        let (count, count_offset) = match (version, row_count) {
+            (XptVersion::V5, _) => (String::new(), None),
+            (XptVersion::V8, Some(rows)) => (format!("{:015}", rows), None),
+            // Placeholder until the member ends; the tail follows the
+            // 48-character header prefix
+            (XptVersion::V8, None) => (format!("{:015}", 0), Some(self.cards.position() + 48)),
+        };
+        self.cards.write_card(&header_card(headers.obs, &count))?;
+
+        self.members.push(name);
+        self.row = vec![b' '; position];
+        self.vars = laid_out;
+        self.rows_written = 0;
+        Ok(count_offset)
+    }
+
+    /// Writes a LABELV8 record for each variable whose label does not fit
+    /// its NAMESTR record, or LABELV9 records when a format or informat
+    /// name does not fit either
+    ///
+    /// Each record is the variable number and the lengths of the name and
+    /// label (and, for LABELV9, format and informat) as big-endian shorts,
+    /// followed by the strings; records are packed back to back.
+    fn write_long_labels(&mut self, vars: &[VarMeta]) -> Result<()> {
+        let encoding = self.options.encoding;
+        let long_format = |var: &VarMeta| {
+            var.format_name.len() > SHORT_NAME_LENGTH || var.informat_name.len() > SHORT_NAME_LENGTH
+        };
+        let long: Vec<&VarMeta> = vars
+            .iter()
+            .filter(|var| {
+                encode_prefix(encoding, &var.label, usize::MAX).len() > SHORT_LABEL_LENGTH
+                    || long_format(var)
+            })
+            .collect();
+        if long.is_empty() {
+            return Ok(());
+        }
+        let v9 = long.iter().any(|var| long_format(var));
+        let mut block = Vec::new();
+        for var in &long {
+            let label = encode_prefix(encoding, &var.label, usize::MAX);
+            let mut fields = vec![var.name.as_bytes(), &label];
+            if v9 {
+                fields.push(var.format_name.as_bytes());
+                fields.push(var.informat_name.as_bytes());
+            }
+            block.extend_from_slice(&var.varnum.to_be_bytes());
+            for field in &fields {
+                block.extend_from_slice(&(field.len() as i16).to_be_bytes());
+            }
+            for field in &fields {
+                block.extend_from_slice(field);
+            }
+        }
+        let header = if v9 { "LABELV9" } else { "LABELV8" };
+        self.cards.write_card(&header_card(header, &long.len().to_string()))?;
+        self.cards.write_record(&block)?;
+        Ok(())
+    }
+
+    /// Encodes and writes one observation of the current member
+    fn write_row(&mut self, row: &[Value]) -> Result<()> {
+        let observation = self.rows_written + 1;
+        if let Some(declared) = self.current.as_ref().and_then(|state| state.declared) {
+            if self.rows_written == declared {
+                return Err(self.unwritable(format!(
+                    "more than the {} observations declared",
+                    declared
+                )));
+            }
+        }
+        if row.len() != self.vars.len() {
+            return Err(self.unwritable(format!(
+                "observation {} has {} values for {} variables",
+                observation,
+                row.len(),
+                self.vars.len()
+            )));
+        }
+        for (var, value) in self.vars.iter().zip(row) {
+            let cell = &mut self.row[var.position..var.position + var.length];
+            match encode_cell(value, var, self.options.encoding, cell) {
+                Ok(None) => {}
+                Ok(Some(message)) => self.diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    offset: self.cards.position() + var.position as u64,
+                    member: self.member_name.clone(),
+                    message: format!("observation {}, variable {}: {}", observation, var.name, message),
+                }),
+                Err(message) => {
+                    return Err(XptError::Unwritable {
+                        member: self.member_name.clone(),
+                        message: format!("observation {}, variable {}: {}", observation, var.name, message),
+                    })
+                }
+            }
+        }
+        self.cards.write_record(&self.row)?;
+        self.rows_written += 1;
+        Ok(())
+    }
+
+    /// Builds the NAMESTR record of a laid-out variable (see
+    /// `parse_name_string` for the layout). In V8 records the name, label
+    /// and format names are cut to fit, and the full name and label length
+    /// follow.
+    fn name_string(&self, var: &VarMeta) -> Result<[u8; NAME_STRING_LENGTH]> {
+        let member = self.member_name.as_deref();
+        let encoding = self.options.encoding;
+        let short = |name: &str| name.get(..SHORT_NAME_LENGTH.min(name.len())).unwrap_or(name).to_string();
+        let label = encode_prefix(encoding, &var.label, usize::MAX);
+        let mut record = [0u8; NAME_STRING_LENGTH];
+        record[0..2].copy_from_slice(&(if var.is_char { 2i16 } else { 1 }).to_be_bytes());
+        record[4..6].copy_from_slice(&(var.length as i16).to_be_bytes());
+        record[6..8].copy_from_slice(&var.varnum.to_be_bytes());
+        put_ascii(&mut record[8..16], &short(&var.name), "variable name", member)?;
+        pad_copy(&mut record[16..56], &encode_prefix(encoding, &var.label, SHORT_LABEL_LENGTH));
+        put_ascii(&mut record[56..64], &short(&var.format_name), "format name", member)?;
+        record[64..66].copy_from_slice(&var.format_len.to_be_bytes());
+        record[66..68].copy_from_slice(&var.format_decimals.to_be_bytes());
+        record[68..70].copy_from_slice(&var.format_justification.to_be_bytes());
+        put_ascii(&mut record[72..80], &short(&var.informat_name), "informat name", member)?;
+        record[80..82].copy_from_slice(&var.informat_len.to_be_bytes());
+        record[82..84].copy_from_slice(&var.informat_decimals.to_be_bytes());
+        record[84..88].copy_from_slice(&(var.position as i32).to_be_bytes());
+        if self.options.version == XptVersion::V8 {
+            put_ascii(&mut record[88..120], &var.name, "variable name", member)?;
+            record[120..122].copy_from_slice(&(label.len() as i16).to_be_bytes());
+        }
+        Ok(record)
+    }
+
+    fn check_member_name(&self, name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(self.unwritable("member has no name".to_string()));
+        }
+        if self.members.iter().any(|m| m.eq_ignore_ascii_case(name)) {
+            return Err(self.unwritable("member name is used twice".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Checks a variable against TS-140 and the variables before it
+    fn check_var(&self, var: &VarMeta, previous: &[VarMeta]) -> Result<()> {
+        if var.name.trim().is_empty() {
+            return Err(self.unwritable(format!("variable {} has no name", previous.len() + 1)));
+        }
+        if previous.iter().any(|v| v.name.eq_ignore_ascii_case(&var.name)) {
+            return Err(self.unwritable(format!("variable name {} is used twice", var.name)));
+        }
+        let (kind, range) = if var.is_char {
+            ("character", 1..=Limits::of(self.options.version).character)
+        } else {
+            ("numeric", 2..=8)
+        };
+        if !range.contains(&var.length) {
+            return Err(self.unwritable(format!(
+                "{} variable {} has length {}, outside {}-{}",
+                kind,
+                var.name,
+                var.length,
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(())
+    }
+
+    /// SAS version and operating system, 8 characters each
+    fn put_origin(&self, field: &mut [u8], version: &str, os: &str, member: Option<&str>) -> Result<()> {
+        let version = if version.is_empty() { DEFAULT_SAS_VERSION } else { version };
+        let os = if os.is_empty() { default_os() } else { os.to_string() };
+        put_ascii(&mut field[0..8], version, "SAS version", member)?;
+        put_ascii(&mut field[8..16], &os, "operating system", member)
+    }
+
+    /// Header datetime, or the current time when none is given
+    fn put_datetime(&self, field: &mut [u8], datetime: &str, member: Option<&str>) -> Result<()> {
+        let datetime = if datetime.is_empty() { &self.now } else { datetime };
+        put_ascii(field, datetime, "datetime", member)
+    }
+
+    /// `name` if it is within the limit of the version; otherwise truncated
+    /// or refused, see [`too_long`](Self::too_long)
+    fn fit_name(&mut self, name: &str, what: &str, limit: fn(&Limits) -> usize) -> Result<String> {
+        let max = limit(&Limits::of(self.options.version));
+        if name.chars().count() <= max {
+            return Ok(name.to_string());
+        }
+        self.too_long(what, name, limit, "characters")?;
+        Ok(name.chars().take(max).collect())
+    }
+
+    /// `text` if it is within the limit of the version once encoded;
+    /// otherwise truncated or refused, see [`too_long`](Self::too_long)
+    fn fit_text(&mut self, text: &str, what: &str, limit: fn(&Limits) -> usize) -> Result<String> {
+        let encoding = self.options.encoding;
+        let bytes = encode_text(encoding, text).ok_or_else(|| {
+            self.unwritable(format!("{} '{}' is not representable in {}", what, text, encoding.name()))
+        })?;
+        let max = limit(&Limits::of(self.options.version));
+        if bytes.len() <= max {
+            return Ok(text.to_string());
+        }
+        self.too_long(what, text, limit, "bytes")?;
+        Ok(text_prefix(encoding, text, max).to_string())
+    }
+
+    /// Metadata too long for the version is refused, or truncated with a
+    /// warning when [`WriteOptions::truncate`] is set
+    fn too_long(&mut self, what: &str, value: &str, limit: fn(&Limits) -> usize, unit: &str) -> Result<()> {
+        let max = limit(&Limits::of(self.options.version));
+        let mut message = format!("{} '{}' is longer than {} {}", what, value, max, unit);
+        if !self.options.truncate {
+            let v8_max = limit(&Limits::of(XptVersion::V8));
+            if v8_max > max {
+                message.push_str(&format!("; V8 files allow {}", v8_max));
+            }
+            return Err(self.unwritable(message));
+        }
+        message.push_str(" and was truncated");
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            offset: self.cards.position(),
+            member: self.member_name.clone(),
+            message,
+        });
+        Ok(())
+    }
+
+    fn unwritable(&self, message: String) -> XptError {
+        XptError::Unwritable {
+            member: self.member_name.clone(),
+            message,
+        }
+    }
+}
+
+/// One member of a transport file being written by an [`XptWriter`]
+///
+/// Observations are encoded and written as they are given. The member ends
+/// when the next one is started or the file is finished.
+pub struct MemberWriter<'a, W> {
+    writer: &'a mut XptWriter<W>,
+}
+
+impl<W: Write> MemberWriter<'_, W> {
+    /// Variables as laid out in the file, with `position` and `varnum`
+    /// recomputed; rows give one value per variable in this order
+    pub fn vars(&self) -> &[VarMeta] {
+        &self.writer.vars
+    }
+
+    /// Observations written to the member so far
+    pub fn rows_written(&self) -> usize {
+        self.writer.rows_written
+    }
+
+    /// Encodes and writes one observation
+    pub fn write_row(&mut self, row: &[Value]) -> Result<()> {
+        self.writer.write_row(row)
+    }
+
+    /// Encodes and writes a batch of observations, returning how many there
+    /// were
+    pub fn write_rows<I>(&mut self, rows: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[Value]>,
+    {
+        let mut written = 0;
+        for row in rows {
+            self.writer.write_row(row.as_ref())?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Ends the member, checking its row count, and returns how many
+    /// observations it has
+    pub fn finish(self) -> Result<usize> {
+        self.writer.end_member()?;
+        Ok(self.writer.rows_written)
+    }
+}
+
+/// Header record `name`, with `tail` (zeros if empty) in its last 32 bytes
+fn header_card(name: &str, tail: &str) -> Card {
+    let tail = if tail.is_empty() { "000000000000000000000000000000" } else { tail };
+    let mut card = blank_card();
+    let text = format!("HEADER RECORD*******{:<8}HEADER RECORD!!!!!!!{}", name, tail);
+    card[..text.len()].copy_from_slice(text.as_bytes());
+    card
+}
+
+fn blank_card() -> Card {
+    [b' '; CARD]
+}
+
+/// Encodes one value into its cell. Returns a note when the value had to
+/// be changed, or why it cannot be written.
+fn encode_cell(
+    value: &Value,
+    var: &VarMeta,
+    encoding: &'static Encoding,
+    cell: &mut [u8],
+) -> std::result::Result<Option<String>, String> {
+    match (value, var.is_char) {
+        (Value::Character(text), true) => match encode_text(encoding, text) {
+            Some(bytes) if bytes.len() <= cell.len() => pad_copy(cell, &bytes),
+            Some(bytes) => {
+                return Err(format!("{} bytes do not fit in {}", bytes.len(), cell.len()))
+            }
+            None => return Err(format!("text is not representable in {}", encoding.name())),
+        },
+        // Character values have no missing value other than blanks
+        (Value::Missing(_), true) => cell.fill(b' '),
+        (Value::Numeric(number), false) => match f64_to_ibm(*number, cell.len()) {
+            Ok(bytes) => cell.copy_from_slice(&bytes[..cell.len()]),
+            Err(IbmEncodeError::NotANumber) => put_missing(cell, IbmMissing::Dot),
+            Err(IbmEncodeError::Underflow(_)) => {
+                cell.fill(0);
+                return Ok(Some(format!(
+                    "{:e} is too small for an IBM double and was written as 0",
+                    number
+                )));
+            }
+            Err(error) => return Err(error.to_string()),
+        },
+        (Value::Missing(kind), false) => {
+            if missing_to_ibm64((*kind).into()).is_none() {
+                return Err(format!("{} is not a valid missing value", kind));
+            }
+            put_missing(cell, (*kind).into());
+        }
+        (Value::Numeric(_), true) => return Err("character variable holds a number".to_string()),
+        (Value::Character(_), false) => return Err("numeric variable holds text".to_string()),
+    }
+    Ok(None)
+}
+
+/// Copies ASCII text into a blank-padded field
+fn put_ascii(field: &mut [u8], text: &str, what: &str, member: Option<&str>) -> Result<()> {
+    if !text.is_ascii() || text.len() > field.len() {
+        return Err(XptError::Unwritable {
+            member: member.map(str::to_string),
+            message: format!("{} '{}' is not ASCII text of up to {} characters", what, text, field.len()),
+        });
+    }
+    pad_copy(field, text.as_bytes());
+    Ok(())
+}
+
+/// Copies `bytes` into `field` and fills the rest with blanks
+fn pad_copy(field: &mut [u8], bytes: &[u8]) {
+    field[..bytes.len()].copy_from_slice(bytes);
+    field[bytes.len()..].fill(b' ');
+}
+
+fn put_missing(cell: &mut [u8], kind: IbmMissing) {
+    if let Some(bytes) = missing_to_ibm64(kind) {
+        cell.copy_from_slice(&bytes[..cell.len()]);
+    }
+}
+
+/// Longest prefix of `text` that fits in `max` bytes once encoded, cut
+/// between characters
+fn text_prefix<'a>(encoding: &'static Encoding, text: &'a str, max: usize) -> &'a str {
+    let mut end = text.len();
+    while encoding.encode(&text[..end]).0.len() > max {
+        end = text[..end].char_indices().next_back().map_or(0, |(index, _)| index);
+    }
+    &text[..end]
+}
+
+/// `text` encoded, cut to at most `max` bytes between characters
+fn encode_prefix(encoding: &'static Encoding, text: &str, max: usize) -> Vec<u8> {
+    encoding.encode(text_prefix(encoding, text, max)).0.into_owned()
+}
+
+/// Text in `encoding`, or `None` if it has characters the encoding lacks
+fn encode_text<'a>(encoding: &'static Encoding, text: &'a str) -> Option<Cow<'a, [u8]>> {
+    let (bytes, _, had_errors) = encoding.encode(text);
+    (!had_errors).then_some(bytes)
+}
+
+/// Operating system name for headers, e.g. `LINUX`
+fn default_os() -> String {
+    std::env::consts::OS
+        .to_ascii_uppercase()
+        .chars()
+        .take(SHORT_NAME_LENGTH)
+        .collect()
+}
+
+/// Formats a time as header records hold it, e.g. `16OCT26:10:20:30` (UTC)
+fn sas_datetime(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, seconds) = ((seconds / 86_400) as i64, seconds % 86_400);
+    // Civil date from days since 1970-01-01, proleptic Gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:02}{}{:02}:{:02}:{:02}:{:02}",
+        day,
+        MONTHS[(month - 1) as usize],
+        year % 100,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::MissingKind;
+    use crate::xpt::read_xpt_v5_from_reader_with;
+    use crate::ReadOptions;
+
+    fn options(version: XptVersion) -> WriteOptions {
+        WriteOptions {
+            version,
+            ..WriteOptions::default()
+        }
+    }
+
+    fn write(datasets: &[Dataset], options: WriteOptions) -> Result<(Vec<u8>, Vec<Diagnostic>)> {
+        let mut out = Vec::new();
+        let diagnostics = write_xpt_to_writer_with(&mut out, datasets, options)?;
+        Ok((out, diagnostics))
+    }
+
+    /// Reads a file back, rejecting any deviation from TS-140
+    fn read_strict(data: &[u8]) -> Vec<Dataset> {
+        let (datasets, diagnostics) =
+            read_xpt_v5_from_reader_with(data, ReadOptions::strict()).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        datasets
+    }
+
+    fn labelled(mut var: VarMeta, label: &str) -> VarMeta {
+        var.label = label.to_string();
+        var
+    }
+
+    fn sample() -> Dataset {
+        let mut aval = labelled(VarMeta::numeric("AVAL"), "Analysis Value");
+        aval.format_name = "BEST".to_string();
+        aval.format_len = 12;
+        let mut short = VarMeta::numeric("SHORT");
+        short.length = 4;
+        let vars = vec![
+            labelled(VarMeta::character("USUBJID", 12), "Unique Subject Identifier"),
+            aval,
+            short,
+        ];
+        let rows = vec![
+            vec![Value::Character("S1-001".into()), Value::Numeric(34.5), Value::Numeric(1.0 / 3.0)],
+            vec![
+                Value::Character("S1-002".into()),
+                Value::Missing(MissingKind::Dot),
+                Value::Missing(MissingKind::Underscore),
+            ],
+            vec![
+                Value::Missing(MissingKind::Dot),
+                Value::Missing(MissingKind::Letter(b'Q')),
+                Value::Numeric(-1024.0),
+            ],
+        ];
+        let mut dataset = Dataset::new("ADLB", vars, rows);
+        dataset.member.label = "Laboratory Analysis".to_string();
+        dataset
+    }
+
+    #[test]
+    fn v5_round_trip() {
+        let dataset = sample();
+        let (data, diagnostics) = write(std::slice::from_ref(&dataset), options(XptVersion::V5)).unwrap();
+        assert!(diagnostics.is_empty());
+        assert_eq!(data.len() % CARD, 0);
+        assert!(data.starts_with(b"HEADER RECORD*******LIBRARY HEADER RECORD!!!!!!!"));
+
+        let read = read_strict(&data);
+        assert_eq!(read.len(), 1);
+        let member = &read[0];
+        assert_eq!(member.name, "ADLB");
+        assert_eq!(member.member.label, "Laboratory Analysis");
+        assert_eq!(member.member.version, XptVersion::V5);
+        for (written, read) in dataset.vars.iter().zip(&member.vars) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.label, written.label);
+            assert_eq!(read.format_name, written.format_name);
+            assert_eq!(read.format_len, written.format_len);
+            assert_eq!(read.is_char, written.is_char);
+            assert_eq!(read.length, written.length);
+        }
+        assert_eq!(member.vars[2].position, 20);
+
+        let third = f64_to_ibm(1.0 / 3.0, 4).unwrap();
+        let expected = vec![
+            vec![
+                Value::Character("S1-001".into()),
+                Value::Numeric(34.5),
+                Value::from_ibm(crate::ibm64_to_f64(&third[..4])),
+            ],
+            vec![
+                Value::Character("S1-002".into()),
+                Value::Missing(MissingKind::Dot),
+                Value::Missing(MissingKind::Underscore),
+            ],
+            // A missing character value is written as blanks
+            vec![
+                Value::Character(String::new()),
+                Value::Missing(MissingKind::Letter(b'Q')),
+                Value::Numeric(-1024.0),
+            ],
+        ];
+        assert_eq!(member.rows, expected);
+    }
+
+    #[test]
+    fn several_members_and_an_empty_one() {
+        let empty = Dataset::new("EMPTY", vec![VarMeta::character("C", 3)], Vec::new());
+        let (data, _) = write(&[sample(), empty], options(XptVersion::V5)).unwrap();
+        let read = read_strict(&data);
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].rows.len(), 3);
+        assert_eq!(read[1].name, "EMPTY");
+        assert!(read[1].rows.is_empty());
+    }
+
+    #[test]
+    fn long_v5_names_are_refused_or_truncated() {
+        let dataset = Dataset::new(
+            "DM",
+            vec![VarMeta::numeric("LONGNAME9")],
+            vec![vec![Value::Numeric(1.0)]],
+        );
+        match write(std::slice::from_ref(&dataset), options(XptVersion::V5)) {
+            Err(XptError::Unwritable { member, message }) => {
+                assert_eq!(member.as_deref(), Some("DM"));
+                assert!(message.contains("V8 files allow 32"), "{}", message);
+            }
+            other => panic!("expected Unwritable, got {:?}", other.map(|_| ())),
+        }
+
+        let truncate = WriteOptions {
+            truncate: true,
+            ..options(XptVersion::V5)
+        };
+        let (data, diagnostics) = write(&[dataset], truncate).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.contains("truncated"));
+        let read = read_strict(&data);
+        assert_eq!(read[0].vars[0].name, "LONGNAME");
+        assert_eq!(read[0].rows, vec![vec![Value::Numeric(1.0)]]);
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        let dataset = Dataset::new(
+            "DM",
+            vec![VarMeta::character("C", 2)],
+            vec![vec![Value::Character("ABC".into())]],
+        );
+        assert!(matches!(
+            write(&[dataset], options(XptVersion::V5)),
+            Err(XptError::Unwritable { .. })
+        ));
+        assert!(matches!(
+            write(&[], options(XptVersion::V5)),
+            Err(XptError::Unwritable { member: None, .. })
+        ));
+    }
+
+    /// Header record named `name`, if the file has one
+    fn find_header(data: &[u8], name: &str) -> Option<usize> {
+        let header = format!("HEADER RECORD*******{:<8}HEADER RECORD!!!!!!!", name);
+        data.windows(header.len()).position(|w| w == header.as_bytes())
+    }
+
+    fn v8_sample(format_name: &str) -> Dataset {
+        let long_label = "Analysis Value Collected at the Scheduled Visit (Derived)";
+        let mut aval = labelled(VarMeta::numeric("ANALYSISVALUE"), long_label);
+        aval.format_name = format_name.to_string();
+        let vars = vec![
+            labelled(VarMeta::character("USUBJID", 12), "Unique Subject Identifier"),
+            aval,
+            VarMeta::character("PARAMETERCODEVALUE", 300),
+        ];
+        let rows = (0..25)
+            .map(|i| {
+                vec![
+                    Value::Character(format!("S1-{:03}", i)),
+                    Value::Numeric(i as f64 * 0.5),
+                    Value::Character("X".repeat(i * 10)),
+                ]
+            })
+            .collect();
+        Dataset::new("ADLBHEMATOLOGY", vars, rows)
+    }
+
+    #[test]
+    fn v8_round_trip_with_long_names_and_labels() {
+        let dataset = v8_sample("BEST");
+        let (data, diagnostics) =
+            write(std::slice::from_ref(&dataset), options(XptVersion::V8)).unwrap();
+        assert!(diagnostics.is_empty());
+        assert!(find_header(&data, "LIBV8").is_some());
+        assert!(find_header(&data, "LABELV8").is_some());
+        assert!(find_header(&data, "LABELV9").is_none());
+        // Too long for V5 without truncation
+        assert!(write(std::slice::from_ref(&dataset), options(XptVersion::V5)).is_err());
+
+        let read = read_strict(&data);
+        let member = &read[0];
+        assert_eq!(member.name, "ADLBHEMATOLOGY");
+        assert_eq!(member.member.version, XptVersion::V8);
+        for (written, read) in dataset.vars.iter().zip(&member.vars) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.label, written.label);
+            assert_eq!(read.format_name, written.format_name);
+            assert_eq!(read.length, written.length);
+        }
+        assert_eq!(member.rows, dataset.rows);
+    }
+
+    #[test]
+    fn long_format_names_need_labelv9() {
+        let dataset = v8_sample("LONGFORMATNAME");
+        let (data, _) = write(std::slice::from_ref(&dataset), options(XptVersion::V8)).unwrap();
+        assert!(find_header(&data, "LABELV9").is_some());
+        assert!(find_header(&data, "LABELV8").is_none());
+
+        let read = read_strict(&data);
+        assert_eq!(read[0].vars[1].format_name, "LONGFORMATNAME");
+        assert_eq!(read[0].vars[1].label, dataset.vars[1].label);
+        assert_eq!(read[0].rows, dataset.rows);
+    }
+
+    #[test]
+    fn v8_headers_declare_the_row_count() {
+        let (data, _) = write(&[v8_sample("BEST")], options(XptVersion::V8)).unwrap();
+        let obs = find_header(&data, "OBSV8").unwrap();
+        assert_eq!(&data[obs + 48..obs + 80], format!("{:<32}", "000000000000025").as_bytes());
+
+        let mut reader =
+            crate::XptReader::with_options(data.as_slice(), ReadOptions::strict()).unwrap();
+        let member = reader.next_member().unwrap().unwrap();
+        assert_eq!(member.rows_left(), Some(25));
+    }
+
+    /// Metadata with fixed datetimes, so that files can be compared byte
+    /// for byte
+    fn dated(mut dataset: Dataset) -> Dataset {
+        for (created, modified) in [
+            (&mut dataset.member.created, &mut dataset.member.modified),
+            (&mut dataset.library.created, &mut dataset.library.modified),
+        ] {
+            *created = "16OCT26:10:20:30".to_string();
+            *modified = "16OCT26:11:00:00".to_string();
+        }
+        dataset.member.name = dataset.name.clone();
+        dataset
+    }
+
+    /// Streams the rows of `datasets` in uneven batches
+    fn stream<W: Write>(
+        writer: &mut XptWriter<W>,
+        datasets: &[Dataset],
+        declare: bool,
+    ) -> Result<()> {
+        for dataset in datasets {
+            let count = dataset.rows.len();
+            let mut member = if declare {
+                writer.start_member_with_count(&dataset.member, &dataset.vars, count)?
+            } else {
+                writer.start_member(&dataset.member, &dataset.vars)?
+            };
+            let mut rows = dataset.rows.iter();
+            if let Some(first) = rows.next() {
+                member.write_row(first)?;
+            }
+            let batch: Vec<Vec<Value>> = rows.by_ref().take(7).cloned().collect();
+            assert_eq!(member.write_rows(&batch)?, batch.len());
+            member.write_rows(rows)?;
+            assert_eq!(member.rows_written(), dataset.rows.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_rows_match_the_dataset_writer() {
+        let datasets = [dated(sample()), dated(v8_sample("BEST"))];
+        for version in [XptVersion::V5, XptVersion::V8] {
+            let datasets = match version {
+                XptVersion::V5 => &datasets[..1],
+                XptVersion::V8 => &datasets[..],
+            };
+            let (expected, _) = write(datasets, options(version)).unwrap();
+
+            let library = &datasets[0].library;
+            let mut writer =
+                XptWriter::with_options(Vec::new(), library, options(version)).unwrap();
+            stream(&mut writer, datasets, true).unwrap();
+            assert_eq!(writer.finish().unwrap(), expected);
+
+            // Seekable output fills in the V8 counts when each member ends
+            let output = std::io::Cursor::new(Vec::new());
+            let mut writer = XptWriter::seekable(output, library, options(version)).unwrap();
+            stream(&mut writer, datasets, false).unwrap();
+            let data = writer.finish().unwrap().into_inner();
+            assert_eq!(data, expected);
+
+            let read = read_strict(&data);
+            for (written, read) in datasets.iter().zip(&read) {
+                assert_eq!(read.rows.len(), written.rows.len());
+            }
+        }
+    }
+
+    #[test]
+    fn v8_row_count_is_patched_on_seekable_output() {
+        let dataset = v8_sample("BEST");
+        let output = std::io::Cursor::new(Vec::new());
+        let mut writer =
+            XptWriter::seekable(output, &dataset.library, options(XptVersion::V8)).unwrap();
+        let info = MemberInfo {
+            name: dataset.name.clone(),
+            ..MemberInfo::default()
+        };
+        let mut member = writer.start_member(&info, &dataset.vars).unwrap();
+        for row in &dataset.rows {
+            member.write_row(row).unwrap();
+        }
+        assert_eq!(member.finish().unwrap(), 25);
+        let data = writer.finish().unwrap().into_inner();
+
+        let obs = find_header(&data, "OBSV8").unwrap();
+        assert_eq!(&data[obs + 48..obs + 63], b"000000000000025");
+        let read = read_strict(&data);
+        assert_eq!(read[0].rows, dataset.rows);
+    }
+
+    #[test]
+    fn v8_needs_a_count_or_seekable_output() {
+        let dataset = v8_sample("BEST");
+        let mut writer =
+            XptWriter::with_options(Vec::new(), &dataset.library, options(XptVersion::V8)).unwrap();
+        match writer.start_member(&dataset.member, &dataset.vars) {
+            Err(XptError::Unwritable { message, .. }) => {
+                assert!(message.contains("row count up front"), "{}", message)
+            }
+            other => panic!("expected Unwritable, got {:?}", other.map(|_| ())),
+        }
+        // V5 headers hold no count
+        let info = MemberInfo {
+            name: "T".to_string(),
+            ..MemberInfo::default()
+        };
+        let mut writer =
+            XptWriter::with_options(Vec::new(), &dataset.library, options(XptVersion::V5)).unwrap();
+        assert!(writer.start_member(&info, &[VarMeta::numeric("X")]).is_ok());
+    }
+
+    #[test]
+    fn declared_row_counts_are_enforced() {
+        let vars = [VarMeta::numeric("X")];
+        let info = MemberInfo {
+            name: "T".to_string(),
+            ..MemberInfo::default()
+        };
+        let row = [Value::Numeric(1.0)];
+        let library = LibraryInfo::default();
+
+        let mut writer = XptWriter::new(Vec::new(), &library).unwrap();
+        let mut member = writer.start_member_with_count(&info, &vars, 1).unwrap();
+        member.write_row(&row).unwrap();
+        assert!(matches!(member.write_row(&row), Err(XptError::Unwritable { .. })));
+
+        let mut writer = XptWriter::new(Vec::new(), &library).unwrap();
+        let mut member = writer.start_member_with_count(&info, &vars, 2).unwrap();
+        member.write_row(&row).unwrap();
+        assert!(matches!(member.finish(), Err(XptError::Unwritable { .. })));
+
+        let writer = XptWriter::new(Vec::new(), &library).unwrap();
+        assert!(matches!(writer.finish(), Err(XptError::Unwritable { member: None, .. })));
+    }
+}
@@ -1,513 +1,2200 @@
-use anyhow::{Result, bail};
-use encoding_rs::WINDOWS_1252;
-use std::io::{Read, BufReader};
-use std::{fs::File, path::Path};
-use crate::ibm370::{ibm64_to_f64, IbmMissing};
+use encoding_rs::Encoding;
+use std::fs::File;
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Seek};
+use std::ops::Range;
+use std::path::Path;
 
-const CARD: usize = 80;
+use crate::card::{Card, CardReader, CARD};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::{Result, XptError};
+use crate::ibm370::{ibm64_missing, ibm64_strided_to_f64, ibm64_to_f64};
+use crate::parallel::map_chunks;
+use crate::text::TextDecoder;
+use crate::{
+    Dataset, LibraryInfo, MemberInfo, ParseMode, ReadOptions, Value, VarMeta, XptVersion,
+};
 
-fn read_card(r: &mut dyn Read) -> Result<[u8; CARD]> {
-    let mut buf = [0u8; CARD];
-    std::io::Read::read_exact(r, &mut buf)?;
-    Ok(buf)
+/// Constants for XPT format parsing
+mod constants {
+    /// Length of a name string record in bytes
+    pub const NAME_STRING_RECORD_LENGTH: usize = 140;
+    /// Length of a name string record written on VAX/VMS
+    pub const VAX_NAME_STRING_RECORD_LENGTH: usize = 136;
+    /// Minimum length for character variables
+    pub const MIN_CHARACTER_LENGTH: usize = 1;
 }
-fn card_str(c: &[u8; CARD]) -> &str {
-    std::str::from_utf8(c).unwrap_or("")
+
+/// How many times the combined length of its values (plus a card) an
+/// observation may span before its npos offsets are taken as corrupt
+const MAX_ROW_SLACK_FACTOR: usize = 2;
+
+/// Observations per thread read ahead before a parallel decode
+const BATCH_ROWS_PER_THREAD: usize = 16 * 1024;
+
+/// Prefix shared by every header record
+const HEADER_PREFIX: &[u8] = b"HEADER RECORD*******";
+/// Suffix following the 8-character record name in every header record
+const HEADER_SUFFIX: &[u8] = b"HEADER RECORD!!!!!!!";
+
+/// Header record types of a transport file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeaderKind {
+    Library,
+    Member,
+    Descriptor,
+    Namestr,
+    /// V8 long label records (name and label)
+    LabelV8,
+    /// V9 long label records (name, label, format and informat)
+    LabelV9,
+    Obs,
 }
-fn trim_ascii(s: &[u8]) -> String {
-    // Use from_utf8_lossy to handle invalid UTF-8 gracefully, then trim nulls and whitespace
-    String::from_utf8_lossy(s)
-        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0')
-        .to_string()
+
+impl HeaderKind {
+    fn of(card: &Card) -> Option<HeaderKind> {
+        parse_header(card).map(|(kind, _)| kind)
+    }
+}
+
+/// Identifies a header record and the transport version it belongs to
+fn parse_header(card: &Card) -> Option<(HeaderKind, XptVersion)> {
+    if !card.starts_with(HEADER_PREFIX) || &card[28..48] != HEADER_SUFFIX {
+        return None;
+    }
+    let header = match &card[20..28] {
+        b"LIBRARY " => (HeaderKind::Library, XptVersion::V5),
+        b"MEMBER  " => (HeaderKind::Member, XptVersion::V5),
+        b"DSCRPTR " => (HeaderKind::Descriptor, XptVersion::V5),
+        b"NAMESTR " => (HeaderKind::Namestr, XptVersion::V5),
+        b"OBS     " => (HeaderKind::Obs, XptVersion::V5),
+        b"LIBV8   " => (HeaderKind::Library, XptVersion::V8),
+        b"MEMBV8  " => (HeaderKind::Member, XptVersion::V8),
+        b"DSCPTV8 " => (HeaderKind::Descriptor, XptVersion::V8),
+        b"NAMSTV8 " => (HeaderKind::Namestr, XptVersion::V8),
+        b"LABELV8 " => (HeaderKind::LabelV8, XptVersion::V8),
+        b"LABELV9 " => (HeaderKind::LabelV9, XptVersion::V8),
+        b"OBSV8   " => (HeaderKind::Obs, XptVersion::V8),
+        _ => return None,
+    };
+    Some(header)
+}
+
+/// True if `card` starts a member, ending the observations of the previous one
+fn is_member_header(card: &Card) -> bool {
+    HeaderKind::of(card) == Some(HeaderKind::Member)
 }
 
-#[derive(Debug, Clone)]
-pub struct VarMeta {
-    pub name: String,
-    pub label: String,
-    pub format_name: String,
-    pub format_len: i16,
-    pub format_decimals: i16,
-    pub informat_name: String,
-    pub informat_len: i16,
-    pub informat_decimals: i16,
-    pub length: usize,        // nlng
-    pub position: usize,      // npos (byte offset within row)
-    pub is_char: bool,        // ntype: 1=numeric, 2=char
-    pub varnum: i16,          // nvar0 (1-based order)
-}
-
-#[derive(Debug)]
-pub struct Dataset {
-    pub name: String,
+/// Parses the ASCII number in the free-form tail of a header record
+fn header_number(card: &Card, range: std::ops::Range<usize>) -> Option<usize> {
+    std::str::from_utf8(&card[range]).ok()?.trim().parse().ok()
+}
+
+/// Kind of deviation from TS-140, deciding the error raised in strict mode
+#[derive(Debug, Clone, Copy)]
+enum Deviation {
+    Header,
+    RowWidth,
+    Truncated,
+}
+
+impl Deviation {
+    /// Severity when recovered from in lenient mode
+    fn severity(self) -> Severity {
+        match self {
+            Deviation::Header => Severity::Warning,
+            Deviation::RowWidth | Deviation::Truncated => Severity::Error,
+        }
+    }
+
+    fn into_error(self, offset: u64, member: Option<String>, message: String) -> XptError {
+        match self {
+            Deviation::Header => XptError::BadHeader {
+                offset,
+                member,
+                message,
+            },
+            Deviation::RowWidth => XptError::InconsistentRowWidth {
+                offset,
+                member: member.unwrap_or_default(),
+                message,
+            },
+            Deviation::Truncated => XptError::Truncated {
+                offset,
+                member,
+                message,
+            },
+        }
+    }
+}
+
+/// Variable layout and identity of one member, read from its headers
+struct MemberHeader {
+    info: MemberInfo,
+    vars: Vec<VarMeta>,
+    row_len: usize,
+    /// Observation count from the V8 OBS header
+    declared_obs: Option<usize>,
+}
+
+/// Where the observations of one member are stored, for random access
+pub(crate) struct MemberLayout {
+    pub info: MemberInfo,
     pub vars: Vec<VarMeta>,
-    pub rows: Vec<Vec<Option<String>>>, // keep as strings for CSV-friendly output
-}
-
-fn be_i16(b: &[u8]) -> i16 { i16::from_be_bytes([b[0], b[1]]) }
-fn be_i32(b: &[u8]) -> i32 { i32::from_be_bytes([b[0], b[1], b[2], b[3]]) }
-
-/// Parse one 140-byte NAMESTR per TS-140 (v5/v6).
-/// struct NAMESTR {
-///   short ntype;     // 0..1
-///   short nhfun;     // 2..3  (hash; often 0)
-///   short nlng;      // 4..5  (length)
-///   short nvar0;     // 6..7  (varnum)
-///   char  nname[8];  // 8..15
-///   char  nlabel[40];// 16..55
-///   char  nform[8];  // 56..63
-///   short nfl;       // 64..65
-///   short nfd;       // 66..67
-///   short nfj;       // 68..69
-///   char  nfill[2];  // 70..71
-///   char  niform[8]; // 72..79
-///   short nifl;      // 80..81
-///   short nifd;      // 82..83
-///   long  npos;      // 84..87 (byte position within observation)
-///   char  rest[52];  // 88..139 (ignored)
-/// };
-fn parse_namestr_140(b: &[u8]) -> VarMeta {
-    if b.len() < 88 {
-        eprintln!("Warning: NAMESTR record too short: {} bytes", b.len());
-    }
-    let ntype = be_i16(&b[0..2]);     // 1=numeric, 2=char
-    let _nhfun = be_i16(&b[2..4]);
-    let nlng  = be_i16(&b[4..6]) as usize;
-    let nvar0 = be_i16(&b[6..8]);
-    let name  = trim_ascii(&b[8..16]);
-    let label = trim_ascii(&b[16..56]);
-    let nform = trim_ascii(&b[56..64]);
-    let nfl   = be_i16(&b[64..66]);
-    let nfd   = be_i16(&b[66..68]);
-    let _nfj  = be_i16(&b[68..70]);
-    // b[70..72] = nfill
-    let niform = trim_ascii(&b[72..80]);
-    let nifl   = be_i16(&b[80..82]);
-    let nifd   = be_i16(&b[82..84]);
-    let npos   = be_i32(&b[84..88]) as usize;
-    
-    // Debug: log raw bytes for first few variables
-    if nvar0 <= 3 {
-        eprintln!("Raw NAMESTR bytes 0-15: {:?}", &b[0..16.min(b.len())]);
-        eprintln!("  ntype={}, nlng={}, nvar0={}, name bytes: {:?}", ntype, nlng, nvar0, &b[8..16]);
+    pub row_len: usize,
+    /// Byte offset of the first observation from the start of the file
+    pub obs_offset: usize,
+    pub row_count: usize,
+}
+
+/// Observation layout and progress of the member whose rows are being read
+struct RowState {
+    row_len: usize,
+    /// Observations left, when the member declares its count
+    rows_left: Option<usize>,
+    /// True if every variable is character, so that a genuine observation
+    /// can be all blanks
+    all_char: bool,
+    /// Byte offset of the first observation
+    obs_start: u64,
+    /// Byte offset where the member ends, once found by scanning ahead
+    end: Option<u64>,
+    /// Observations returned so far
+    rows_read: usize,
+    /// All-blank observations read but not yet returned: they are data
+    /// unless they turn out to be the padding at the end of the member
+    blank_rows: usize,
+    /// Blank observations to return before reading on
+    replay_blanks: usize,
+    /// True if a non-blank observation is waiting in `XptReader::held`
+    held: bool,
+    /// True once the end of the member has been reached
+    ended: bool,
+}
+
+impl RowState {
+    fn new(header: &MemberHeader, obs_start: u64) -> Self {
+        RowState {
+            row_len: header.row_len,
+            rows_left: header.declared_obs,
+            all_char: header.vars.iter().all(|v| v.is_char),
+            obs_start,
+            end: None,
+            rows_read: 0,
+            blank_rows: 0,
+            replay_blanks: 0,
+            held: false,
+            ended: false,
+        }
     }
 
-    VarMeta {
-        name, label,
-        format_name: nform, format_len: nfl, format_decimals: nfd,
-        informat_name: niform, informat_len: nifl, informat_decimals: nifd,
-        length: nlng, position: npos,
-        is_char: ntype != 1,
-        varnum: nvar0,
+    /// True if rows read ahead are waiting to be returned
+    fn has_pending(&self) -> bool {
+        self.held || self.replay_blanks > 0
     }
 }
 
-fn decode_char(bytes: &[u8]) -> String {
-    let (cow, _, _) = WINDOWS_1252.decode(bytes);
-    cow.trim_end().to_string()
+/// Streaming reader for SAS XPORT Version 5 and 8 transport files
+///
+/// Members are visited in file order with [`next_member`](Self::next_member);
+/// each returned [`MemberReader`] decodes its rows lazily, so memory use does
+/// not depend on the size of the file.
+///
+/// ```no_run
+/// use xpttools::XptReader;
+///
+/// let mut reader = XptReader::open("LB.xpt")?;
+/// while let Some(mut member) = reader.next_member()? {
+///     println!("{} ({} variables)", member.info().name, member.vars().len());
+///     for row in &mut member {
+///         let row = row?;
+///         // ...
+///     }
+/// }
+/// # Ok::<(), xpttools::XptError>(())
+/// ```
+///
+/// Deviations from TS-140 are errors in [`ParseMode::Strict`]; in the
+/// default lenient mode they are recovered from where possible and collected
+/// in [`diagnostics`](Self::diagnostics).
+pub struct XptReader<R> {
+    cards: CardReader<R>,
+    options: ReadOptions,
+    library: LibraryInfo,
+    has_library: bool,
+    current: Option<RowState>,
+    raw: Vec<u8>,
+    /// Observation read ahead while deciding whether blank rows are padding
+    held: Vec<u8>,
+    /// Name of the member being read, for error reports
+    member_name: Option<String>,
+    text: TextDecoder,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl XptReader<BufReader<File>> {
+    /// Opens a transport file for streaming
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        XptReader::open_with(path, ReadOptions::default())
+    }
+
+    /// Opens a transport file for streaming with the given options
+    pub fn open_with<P: AsRef<Path>>(path: P, options: ReadOptions) -> Result<Self> {
+        let file = File::open(path)?;
+        XptReader::seekable(BufReader::new(file), options)
+    }
 }
 
-#[allow(dead_code)]
-fn looks_like_header(card: &[u8]) -> bool {
-    card_str(&card.try_into().unwrap_or([0u8; 80])).starts_with("HEADER RECORD*******")
+impl<R: Read + Seek> XptReader<R> {
+    /// Like [`with_options`](Self::with_options), for input that can seek:
+    /// skipped observations are then passed over by seeking, once their
+    /// cards have been scanned for the next member header.
+    pub fn seekable(inner: R, options: ReadOptions) -> Result<Self> {
+        XptReader::from_cards(CardReader::seekable(inner)?, options)
+    }
 }
 
-/// Read one V5/V6 transport file from a reader; return all members (datasets).
-pub fn read_xpt_v5_from_reader<R: Read>(mut r: BufReader<R>) -> Result<Vec<Dataset>> {
-    // Try to read LIBRARY header (3 cards: banner + 2 real headers)
-    // Some XPT files are single-member and don't have LIBRARY wrapper
-    let lib0 = match read_card(&mut r) {
-        Ok(c) => c,
-        Err(e) => bail!("Failed to read first card (file may be too small or corrupted): {}", e),
-    };
-    let lib0_str = card_str(&lib0);
-    let has_library_header = lib0_str.starts_with("HEADER RECORD*******LIBRARY HEADER RECORD");
-    
-    let mut first_card = lib0; // Keep track of first card for single-member files
-    
-    if has_library_header {
-        // Full library format - read the two additional header cards
-        let _lib1 = read_card(&mut r)?; // real header 1
-        let _lib2 = read_card(&mut r)?; // real header 2
-    } else {
-        // Check if first card looks like any valid XPT header
-        if !lib0_str.starts_with("HEADER RECORD*******") {
-            bail!("File does not start with a valid XPT header. First 80 bytes: {}", 
-                  String::from_utf8_lossy(&first_card[..first_card.len().min(80)]));
+impl<'a> XptReader<io::Cursor<&'a [u8]>> {
+    /// Like [`seekable`](Self::seekable), for a file held in memory: the
+    /// observations skipped are scanned where they are, without copying
+    pub(crate) fn in_memory(data: &'a [u8], options: ReadOptions) -> Result<Self> {
+        XptReader::from_cards(CardReader::in_memory(io::Cursor::new(data)), options)
+    }
+}
+
+impl<R: Read> XptReader<R> {
+    /// Wraps a reader positioned at the start of a transport file and
+    /// consumes the LIBRARY header, if present.
+    pub fn new(inner: R) -> Result<Self> {
+        XptReader::with_options(inner, ReadOptions::default())
+    }
+
+    /// Like [`new`](Self::new), with the given options
+    pub fn with_options(inner: R, options: ReadOptions) -> Result<Self> {
+        XptReader::from_cards(CardReader::new(inner), options)
+    }
+
+    fn from_cards(cards: CardReader<R>, options: ReadOptions) -> Result<Self> {
+        let mut reader = XptReader {
+            cards,
+            text: TextDecoder::new(options.encoding),
+            options,
+            library: LibraryInfo::default(),
+            has_library: false,
+            current: None,
+            raw: Vec::new(),
+            held: Vec::new(),
+            member_name: None,
+            diagnostics: Vec::new(),
+        };
+
+        let first = match reader.cards.next_card() {
+            Ok(Some(card)) => card,
+            Ok(None) => return Err(XptError::NotXpt("file is empty".to_string())),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(XptError::NotXpt(
+                    "file too small to be a valid XPT file".to_string(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match parse_header(&first) {
+            Some((HeaderKind::Library, version)) => {
+                // Two real header cards follow the LIBRARY header
+                let first_real = reader.expect_card("library header")?;
+                let second_real = reader.expect_card("library header")?;
+                reader.library = parse_library_header(&first_real, &second_real, version);
+                reader.has_library = true;
+            }
+            Some(_) => {
+                reader.cards.push_back(first);
+                reader.deviation(Deviation::Header, 0, "LIBRARY header missing".to_string())?;
+            }
+            None if first.starts_with(HEADER_PREFIX) => {
+                return Err(XptError::UnsupportedVersion(format!(
+                    "unknown header record '{}'",
+                    String::from_utf8_lossy(&first[20..28]).trim_end()
+                )))
+            }
+            None if first.starts_with(b"**COMPRESSED**") => {
+                return Err(XptError::UnsupportedVersion(
+                    "CPORT file (use PROC CIMPORT)".to_string(),
+                ))
+            }
+            None => {
+                return Err(XptError::NotXpt(format!(
+                    "file does not start with a header record: {}",
+                    String::from_utf8_lossy(&first[..48])
+                )))
+            }
         }
+
+        Ok(reader)
     }
 
-    let mut out = Vec::new();
-    let mut peeked_namestr_card: Option<[u8; CARD]> = None; // Store a card that might be NAMESTR
+    /// Metadata from the LIBRARY header (empty if the file has none)
+    pub fn library(&self) -> &LibraryInfo {
+        &self.library
+    }
 
-    loop {
-        // MEMBER HEADER (2 cards) + MEMBER HEADER DATA (2 cards)
-        // For single-member files without LIBRARY header, reuse the first card we read
-        let mh0 = if !has_library_header && out.is_empty() {
-            first_card
-        } else {
-            match read_card(&mut r) { 
-                Ok(c) => c, 
-                Err(_) => break 
+    /// Encoding used for labels and character values; with
+    /// [`TextEncoding::Auto`](crate::TextEncoding::Auto) this is `None` until
+    /// the first non-ASCII text has been read
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.text.encoding()
+    }
+
+    /// Problems recovered from so far, in file order
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Takes the problems recovered from so far, leaving the list empty
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Moves to the next member, skipping any unread rows of the current one.
+    /// Returns `None` once the file has no more members.
+    pub fn next_member(&mut self) -> Result<Option<MemberReader<'_, R>>> {
+        self.skip_rest_of_member()?;
+        match self.read_member_header()? {
+            Some(header) => self.start_member(header).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Moves to the next member named `name` (case-insensitive), skipping
+    /// everything before it. Returns `None` if no later member has that name.
+    pub fn find_member(&mut self, name: &str) -> Result<Option<MemberReader<'_, R>>> {
+        self.skip_rest_of_member()?;
+        while let Some(header) = self.read_member_header()? {
+            if header.info.name.eq_ignore_ascii_case(name) {
+                return self.start_member(header).map(Some);
             }
+            self.current = Some(RowState::new(&header, self.cards.position()));
+            self.skip_rest_of_member()?;
+        }
+        Ok(None)
+    }
+
+    /// Reads the headers of the next member and skips over its rows,
+    /// recording where they are stored
+    pub(crate) fn next_member_layout(&mut self) -> Result<Option<MemberLayout>> {
+        self.skip_rest_of_member()?;
+        let header = match self.read_member_header()? {
+            Some(header) => header,
+            None => return Ok(None),
         };
-        
-        // Check what type of header this is
-        // Note: Headers may have varying numbers of spaces, so we check for key words
-        let mh0_str = card_str(&mh0);
-        let is_member_header = mh0_str.contains("MEMBER") && mh0_str.contains("HEADER");
-        let is_namestr_header = mh0_str.contains("NAMESTR") && mh0_str.contains("HEADER");
-        let is_dscrptr_header = mh0_str.contains("DSCRPTR") && mh0_str.contains("HEADER");
-        
-        if !has_library_header && out.is_empty() {
-            // Single-member file - might start with MEMBER, DSCRPTR, or NAMESTR
-            if is_member_header {
-                // Has member header - read the rest
-                let _mh1 = read_card(&mut r)?;
-                let _mhd0 = read_card(&mut r)?;
-                let _mhd1 = read_card(&mut r)?;
-                
-                // DSCRPTR HEADER (2 cards) - optional, some files skip it
-                // Try to read it, but if it's NAMESTR, we'll use it as NAMESTR header
-                let dh0_result = read_card(&mut r);
-                match dh0_result {
-                    Ok(dh0) => {
-                        let dh0_str = card_str(&dh0);
-                        if dh0_str.contains("DSCRPTR") && dh0_str.contains("HEADER") {
-                            // It's DSCRPTR - read second card
-                            let _dh1 = read_card(&mut r)?;
-                        } else if dh0_str.contains("NAMESTR") && dh0_str.contains("HEADER") {
-                            // It's actually NAMESTR - store it to use as nh0
-                            peeked_namestr_card = Some(dh0);
-                        }
-                        // If it's something else, we'll try to continue
-                    }
-                    Err(_) => {
-                        // EOF - file might be corrupted or incomplete
-                    }
-                }
-            } else if is_dscrptr_header {
-                // Starts with DSCRPTR - read second card
-                let _dh1 = read_card(&mut r)?;
-            } else if !is_namestr_header {
-                // Doesn't start with any expected header - try to continue anyway
-                // This handles files that might have slight variations
-            }
-        } else if is_member_header {
-            // Full format: read member header cards
-            let _mh1 = read_card(&mut r)?;
-            let _mhd0 = read_card(&mut r)?;
-            let _mhd1 = read_card(&mut r)?;
-            
-            // DSCRPTR HEADER (2 cards) - optional, some files skip it
-            // Try to read it, but if it's NAMESTR, we'll use it as NAMESTR header
-            let dh0_result = read_card(&mut r);
-            match dh0_result {
-                Ok(dh0) => {
-                    let dh0_str = card_str(&dh0);
-                    if dh0_str.contains("DSCRPTR") && dh0_str.contains("HEADER") {
-                        // It's DSCRPTR - read second card
-                        let _dh1 = read_card(&mut r)?;
-                    } else if dh0_str.contains("NAMESTR") && dh0_str.contains("HEADER") {
-                        // It's actually NAMESTR - store it to use as nh0
-                        peeked_namestr_card = Some(dh0);
-                    }
-                    // If it's something else, we'll try to continue
+        let obs_offset = self.cards.position() as usize;
+        self.current = Some(RowState::new(&header, obs_offset as u64));
+        let vars = self.select_columns(header.vars);
+        let row_count = self.skip_rest_of_member()?;
+        Ok(Some(MemberLayout {
+            info: header.info,
+            vars,
+            row_len: header.row_len,
+            obs_offset,
+            row_count,
+        }))
+    }
+
+    fn start_member(&mut self, header: MemberHeader) -> Result<MemberReader<'_, R>> {
+        self.current = Some(RowState::new(&header, self.cards.position()));
+        let vars = self.select_columns(header.vars);
+        let index = self.skip_rows(self.options.skip)?;
+        let remaining = self.options.limit;
+        Ok(MemberReader {
+            reader: self,
+            info: header.info,
+            vars,
+            row_len: header.row_len,
+            index,
+            remaining,
+        })
+    }
+
+    /// Applies the column selection of the options to a member's variables
+    fn select_columns(&mut self, vars: Vec<VarMeta>) -> Vec<VarMeta> {
+        let (selected, missing) = self.options.columns.select(vars);
+        for name in missing {
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                offset: self.cards.position(),
+                member: self.member_name.clone(),
+                message: format!("column {} not found", name),
+            });
+        }
+        selected
+    }
+
+    /// Reads the headers of the next member
+    fn read_member_header(&mut self) -> Result<Option<MemberHeader>> {
+        let mut info = MemberInfo::default();
+        self.member_name = None;
+
+        let mut card = match self.next_card()? {
+            Some(card) => card,
+            None => return Ok(None),
+        };
+
+        // MEMBER and DSCRPTR headers are followed by two member header data
+        // cards; some single-member files omit them and start at NAMESTR.
+        let mut versions = Vec::new();
+        let mut namestr_len = constants::NAME_STRING_RECORD_LENGTH;
+        if let Some((HeaderKind::Member, version)) = parse_header(&card) {
+            versions.push(version);
+            // NAMESTR record length: four ASCII digits at offset 74, 0136 on VAX/VMS
+            match header_number(&card, 74..78) {
+                Some(
+                    len @ (constants::NAME_STRING_RECORD_LENGTH
+                    | constants::VAX_NAME_STRING_RECORD_LENGTH),
+                ) => namestr_len = len,
+                other => {
+                    let offset = self.last_card_offset() + 74;
+                    let found = other.map_or("none".to_string(), |len| len.to_string());
+                    self.deviation(
+                        Deviation::Header,
+                        offset,
+                        format!(
+                            "MEMBER header declares NAMESTR length {}, expected 140 or 136",
+                            found
+                        ),
+                    )?;
                 }
-                Err(_) => {
-                    // EOF - file might be corrupted or incomplete
+            }
+            card = self.expect_card("DSCRPTR header")?;
+        } else {
+            let offset = self.last_card_offset();
+            self.deviation(Deviation::Header, offset, "MEMBER header missing".to_string())?;
+        }
+        if let Some((HeaderKind::Descriptor, version)) = parse_header(&card) {
+            versions.push(version);
+            let first_data = self.expect_card("member header data")?;
+            let second_data = self.expect_card("member header data")?;
+            info = parse_member_header(&first_data, &second_data, version, &mut self.text);
+            self.member_name = Some(info.name.clone());
+            let offset = self.last_card_offset();
+            self.report_text(offset);
+            card = self.expect_card("NAMESTR header")?;
+        } else {
+            let offset = self.last_card_offset();
+            self.deviation(Deviation::Header, offset, "DSCRPTR header missing".to_string())?;
+        }
+        let namestr_offset = self.last_card_offset();
+        let version = match parse_header(&card) {
+            Some((HeaderKind::Namestr, version)) => version,
+            _ => return Err(self.unexpected_header(&card, "NAMESTR")),
+        };
+        versions.push(version);
+        info.version = version;
+
+        // Variable count: four ASCII digits at offset 54
+        let declared_count = header_number(&card, 54..58).unwrap_or(0);
+
+        // NAMESTR records are packed back to back across cards, up to the
+        // LABELV8/LABELV9 or OBS header
+        let block_offset = self.cards.position();
+        let (name_string_block, mut next_header) = self.read_until_header("OBS header")?;
+
+        let available = name_string_block.len() / namestr_len;
+        if declared_count != available {
+            self.deviation(
+                Deviation::Header,
+                namestr_offset + 54,
+                format!(
+                    "NAMESTR header declares {} variables but {} records follow",
+                    declared_count, available
+                ),
+            )?;
+        }
+        let record_count = if declared_count > 0 && declared_count <= available {
+            declared_count
+        } else {
+            available
+        };
+        if record_count == 0 {
+            return Err(self.bad_header("member has no NAMESTR records"));
+        }
+
+        let padding_start = record_count * namestr_len;
+        if record_count == available && !is_blank(&name_string_block[padding_start..]) {
+            self.deviation(
+                Deviation::Header,
+                block_offset + padding_start as u64,
+                "NAMESTR records are not padded with blanks".to_string(),
+            )?;
+        }
+
+        let mut records = Vec::with_capacity(record_count);
+        for (index, data) in name_string_block
+            .chunks_exact(namestr_len)
+            .take(record_count)
+            .enumerate()
+        {
+            let offset = block_offset + (index * namestr_len) as u64;
+            for problem in check_name_string(data, version) {
+                self.deviation(
+                    Deviation::Header,
+                    offset,
+                    format!("NAMESTR record {}: {}", index + 1, problem),
+                )?;
+            }
+            records.push(parse_name_string(data, version, &mut self.text));
+            self.report_text(offset);
+        }
+
+        // Long names and labels that do not fit the NAMESTR record
+        let label_kind = HeaderKind::of(&next_header);
+        if matches!(label_kind, Some(HeaderKind::LabelV8 | HeaderKind::LabelV9)) {
+            versions.push(XptVersion::V8);
+            let label_count = header_number(&next_header, 48..80).unwrap_or(0);
+            let label_offset = self.cards.position();
+            let (label_block, obs_header) = self.read_until_header("OBS header")?;
+            let problems = apply_long_labels(
+                &mut records,
+                &label_block,
+                label_count,
+                label_kind == Some(HeaderKind::LabelV9),
+                &mut self.text,
+            );
+            self.report_text(label_offset);
+            for problem in problems {
+                self.deviation(Deviation::Header, label_offset, problem)?;
+            }
+            next_header = obs_header;
+        }
+        let obs_version = match parse_header(&next_header) {
+            Some((HeaderKind::Obs, version)) => version,
+            _ => return Err(self.unexpected_header(&next_header, "OBS")),
+        };
+        versions.push(obs_version);
+        let count_offset = self.last_card_offset() + 48;
+        let declared_obs = match version {
+            XptVersion::V8 => {
+                let count = header_number(&next_header, 48..80);
+                if count.is_none() {
+                    let offset = count_offset;
+                    self.deviation(
+                        Deviation::Header,
+                        offset,
+                        "OBSV8 header has no observation count".to_string(),
+                    )?;
                 }
+                count
             }
-        } else if !is_namestr_header && !is_dscrptr_header {
-            // Not a recognized header - if we haven't found anything yet, 
-            // this might be a file that starts directly with NAMESTR or has a different structure
-            // Try to continue and look for NAMESTR header in the next cards
-            if out.is_empty() && !has_library_header {
-                // For single-member files, try to find NAMESTR by reading ahead
-                // But we've already consumed mh0, so we need to check if the next card is NAMESTR
-                // Actually, let's just try to read the next card and see if it's NAMESTR
-            } else if out.is_empty() {
-                let card_preview = card_str(&mh0);
-                bail!("Expected MEMBER, DSCRPTR, or NAMESTR header but found: {}", 
-                      card_preview.chars().take(60).collect::<String>());
-            } else {
-                // If we've already parsed a dataset, this might be the end
-                break;
+            XptVersion::V5 => None,
+        };
+
+        if versions.iter().any(|&v| v != version)
+            || (self.has_library && self.library.version != version)
+        {
+            self.deviation(
+                Deviation::Header,
+                namestr_offset,
+                "member mixes V5 and V8 header records".to_string(),
+            )?;
+        }
+
+        let mut vars = build_vars(records);
+        self.check_positions(&mut vars, block_offset)?;
+        for (index, var) in vars.iter().enumerate() {
+            if vars[..index]
+                .iter()
+                .any(|other| other.name.eq_ignore_ascii_case(&var.name))
+            {
+                self.deviation(
+                    Deviation::Header,
+                    block_offset,
+                    format!("duplicate variable name {}", var.name),
+                )?;
             }
         }
+        // Observation length: the end of the last value, which covers any
+        // gaps between values
+        let row_len = vars
+            .iter()
+            .map(|v| v.position + v.length)
+            .max()
+            .unwrap_or(0);
+        if row_len == 0 {
+            return Err(XptError::InconsistentRowWidth {
+                offset: self.cards.position(),
+                member: info.name,
+                message: "variables have zero total length".to_string(),
+            });
+        }
 
-        // NAMESTR HEADER (2 cards) — second card has var count at offset 54, ASCII
-        let nh0 = if let Some(card) = peeked_namestr_card.take() {
-            // We already read the NAMESTR header card (it was after MEMBER, not DSCRPTR)
-            card
-        } else if !has_library_header && out.is_empty() && is_namestr_header {
-            // We already have nh0 in mh0
-            mh0
-        } else {
-            // Need to read NAMESTR header card
-            read_card(&mut r)?
+        let declared_obs = match declared_obs {
+            Some(count) => self.check_declared_obs(count, row_len, count_offset)?,
+            None => None,
+        };
+
+        if info.name.is_empty() {
+            info.name = "XPT Dataset".to_string();
+        }
+
+        Ok(Some(MemberHeader {
+            info,
+            vars,
+            row_len,
+            declared_obs,
+        }))
+    }
+
+    /// Checks that the rest of the input can hold `count` declared rows of
+    /// `row_len` bytes. A count that cannot be right is reported and
+    /// dropped, so that the end of the member is found as for V5.
+    fn check_declared_obs(
+        &mut self,
+        count: usize,
+        row_len: usize,
+        offset: u64,
+    ) -> Result<Option<usize>> {
+        let span = count.checked_mul(row_len).map(|bytes| bytes as u64);
+        let message = match (span, self.cards.remaining()) {
+            (None, _) => format!("OBSV8 header declares {} observations, too many to address", count),
+            (Some(span), Some(left)) if span > left => format!(
+                "OBSV8 header declares {} observations of {} bytes, more than the {} bytes left",
+                count, row_len, left
+            ),
+            _ => return Ok(Some(count)),
+        };
+        self.deviation(Deviation::Header, offset, message)?;
+        Ok(None)
+    }
+
+    /// Checks the npos offsets of the variables against their lengths
+    ///
+    /// Offsets may leave gaps or store values in a different order from the
+    /// variable numbers, but values must not overlap. If every offset is
+    /// zero the writer did not fill them in, and if one runs far past the
+    /// combined length of the values it is corrupt; either way values are
+    /// taken to be stored back to back.
+    fn check_positions(&mut self, vars: &mut [VarMeta], offset: u64) -> Result<()> {
+        if vars.len() > 1 && vars.iter().all(|v| v.position == 0) {
+            self.deviation(
+                Deviation::Header,
+                offset,
+                "NAMESTR records have no value offsets (npos); assuming values are contiguous"
+                    .to_string(),
+            )?;
+            lay_out_contiguously(vars);
+            return Ok(());
+        }
+
+        // Gaps are alignment slack, so the observation cannot be much longer
+        // than its values; the row buffer is sized from the furthest end
+        let total: usize = vars.iter().map(|v| v.length).sum();
+        if let Some(far) = vars
+            .iter()
+            .find(|v| v.position + v.length > total * MAX_ROW_SLACK_FACTOR + CARD)
+        {
+            let message = format!(
+                "{} ends at byte {}, far beyond the {} bytes of all values; assuming values are contiguous",
+                far.name,
+                far.position + far.length,
+                total
+            );
+            self.deviation(Deviation::Header, offset, message)?;
+            lay_out_contiguously(vars);
+            return Ok(());
+        }
+
+        let mut by_position: Vec<&VarMeta> = vars.iter().collect();
+        by_position.sort_by_key(|v| v.position);
+        for pair in by_position.windows(2) {
+            if pair[0].position + pair[0].length > pair[1].position {
+                let message = format!(
+                    "{} (bytes {}..{}) overlaps {} at byte {}",
+                    pair[0].name,
+                    pair[0].position,
+                    pair[0].position + pair[0].length,
+                    pair[1].name,
+                    pair[1].position
+                );
+                self.deviation(Deviation::Header, offset, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next raw observation of the current member into `self.raw`.
+    /// Returns `false` at the end of the member, leaving the stream in front
+    /// of the next MEMBER header.
+    fn read_raw_row(&mut self) -> Result<bool> {
+        let mut state = match self.current.take() {
+            Some(state) => state,
+            None => return Ok(false),
         };
-        let nh1 = read_card(&mut r)?;
-        let nh0_str = card_str(&nh0);
-        if !nh0_str.contains("NAMESTR") || !nh0_str.contains("HEADER") {
-            bail!("NAMESTR header missing. Found: {}", nh0_str.chars().take(60).collect::<String>());
-        }
-        // var count: four ASCII digits starting at column offset 54 (0-based)
-        // But some files don't have this, so we'll calculate from the space between headers
-        let nvars_from_header: usize = std::str::from_utf8(&nh1[54..58]).unwrap_or("0000").trim().parse().unwrap_or(0);
-        
-        // Debug: show nh1 content
-        eprintln!("nh1 (second NAMESTR header card): first 32 bytes: {:?}, as string: {}", 
-                 &nh1[0..32], card_str(&nh1).chars().take(40).collect::<String>());
-        
-        // IMPORTANT: nh1 is actually the first 80 bytes of the first NAMESTR record!
-        // We need to include it in the buffer, not skip it
-        let mut namestr_buffer = Vec::new();
-        namestr_buffer.extend_from_slice(&nh1);
-
-        // NAMESTR records — each is 140 bytes, streamed across 80-byte cards
-        // Spec also allows 136 bytes on VAX; we assume 140 here.
-        // Records are stored sequentially (not card-aligned), so we extract at 140-byte intervals
-        let namestr_len = 140usize;
-        
-        // After the NAMESTR header (2 cards = 160 bytes), the data starts at the next card boundary
-        // But we've already read nh0 and nh1, so the next card should be the start of NAMESTR data
-        // However, we need to check if there's padding or if data starts immediately
-        
-        // Strategy: Read cards until we find OBS header, then calculate nvars from what we read
-        // This is more robust than relying on the header field
-        // Note: nh1 is already in namestr_buffer above
-        let mut cards_read = 0;
-        let mut found_obs = false;
-        let mut obs_card: Option<[u8; CARD]> = None;
-        
-        // Read cards and look for OBS header
-        // Skip leading zero-filled cards (padding) before NAMESTR data
-        let mut skip_zeros = true;
+        let found = self.next_raw_row(&mut state)?;
+        if found {
+            state.rows_read += 1;
+            self.current = Some(state);
+        }
+        Ok(found)
+    }
+
+    /// Observations are padded with blanks to a whole card at the end of a
+    /// member, so when rows are shorter than a card the padding can look like
+    /// all-blank rows. V8 members declare their row count, which is checked
+    /// against the data; otherwise blank rows are held back until a
+    /// non-blank row shows they are data, or the end of the member shows how
+    /// many of them fit in the padding.
+    fn next_raw_row(&mut self, state: &mut RowState) -> Result<bool> {
         loop {
-            let card = match read_card(&mut r) {
-                Ok(c) => c,
-                Err(_) => break, // EOF
+            self.raw.resize(state.row_len, 0);
+            if state.replay_blanks > 0 {
+                state.replay_blanks -= 1;
+                self.raw.fill(b' ');
+                return Ok(true);
+            }
+            if state.held {
+                state.held = false;
+                std::mem::swap(&mut self.raw, &mut self.held);
+                return Ok(true);
+            }
+            if state.ended {
+                return Ok(false);
+            }
+            if state.rows_left == Some(0) {
+                self.check_declared_end(state)?;
+                continue;
+            }
+
+            let filled = match self.cards.read_record(&mut self.raw, is_member_header) {
+                Ok(filled) => filled,
+                Err(e) => return Err(self.io_error(e)),
             };
-            cards_read += 1;
-            let card_str_check = card_str(&card);
-            
-            // Debug: show first few cards
-            if cards_read <= 5 {
-                eprintln!("Card {}: first 32 bytes: {:?}, as string: {}", 
-                         cards_read, &card[0..32.min(card.len())], 
-                         card_str_check.chars().take(40).collect::<String>());
-            }
-            
-            if card_str_check.contains("OBS") && card_str_check.contains("HEADER") {
-                // Found OBS header - store it and break
-                obs_card = Some(card);
-                found_obs = true;
-                break;
-            } else {
-                // Check if this card is all zeros (padding)
-                let is_zero_card = card.iter().all(|&b| b == 0);
-                
-                if skip_zeros && is_zero_card {
-                    // Skip zero-filled padding cards at the start
-                    eprintln!("Skipping zero-filled card {}", cards_read);
-                    continue;
-                } else {
-                    // We've found non-zero data, stop skipping
-                    skip_zeros = false;
-                    // Not OBS header yet - add to buffer
-                    namestr_buffer.extend_from_slice(&card);
+            if filled < state.row_len {
+                state.ended = true;
+                self.end_of_member(state, filled)?;
+                continue;
+            }
+            match state.rows_left.as_mut() {
+                // A blank row with numeric values in the last card is padding
+                Some(&mut left)
+                    if !state.all_char && is_blank(&self.raw) && self.at_member_end()? =>
+                {
+                    state.ended = true;
+                    let offset = self.cards.position() - state.row_len as u64;
+                    self.deviation(
+                        Deviation::Truncated,
+                        offset,
+                        format!("{} declared observations are missing", left),
+                    )?;
+                }
+                Some(left) => {
+                    *left -= 1;
+                    return Ok(true);
                 }
+                None if is_blank(&self.raw) => state.blank_rows += 1,
+                None if state.blank_rows > 0 => {
+                    // The blank rows were data: return them, then this row
+                    std::mem::swap(&mut self.raw, &mut self.held);
+                    state.held = true;
+                    state.replay_blanks = std::mem::take(&mut state.blank_rows);
+                }
+                None => return Ok(true),
             }
-            
-            // Safety limit: if we've read too many cards without finding OBS, something's wrong
-            if cards_read > 1000 {
-                bail!("Read too many cards ({}) without finding OBS header. Last card: {}", 
-                      cards_read, card_str_check.chars().take(60).collect::<String>());
-            }
-        }
-        
-        if !found_obs {
-            bail!("OBS header not found after reading {} cards of NAMESTR data", cards_read);
-        }
-        
-        // Debug: show first few bytes of buffer
-        if namestr_buffer.len() >= 32 {
-            eprintln!("First 32 bytes of namestr_buffer: {:?}", &namestr_buffer[0..32]);
-        }
-        
-        // Calculate nvars from the buffer size
-        // The buffer contains all cards between NAMESTR and OBS headers
-        // Each variable is 140 bytes, stored sequentially (not card-aligned)
-        let calculated_nvars = namestr_buffer.len() / namestr_len;
-        
-        let nvars = if nvars_from_header > 0 && nvars_from_header == calculated_nvars {
-            // Header value matches calculated value - use it
-            nvars_from_header
-        } else if calculated_nvars > 0 {
-            // Use calculated value (more reliable)
-            calculated_nvars
-        } else if nvars_from_header > 0 {
-            // Header says we have vars but buffer is empty - use header value
-            nvars_from_header
+        }
+    }
+
+    /// Settles the row count once the member has ended after `filled` bytes
+    /// of an incomplete row
+    fn end_of_member(&mut self, state: &mut RowState, filled: usize) -> Result<()> {
+        let end = self.cards.position();
+        if let Some(left) = state.rows_left {
+            return self.deviation(
+                Deviation::Truncated,
+                end,
+                format!("{} declared observations are missing", left),
+            );
+        }
+        if !self.raw[..filled].iter().all(|&b| b == b' ' || b == 0) {
+            let message = format!(
+                "{} trailing bytes do not form a whole {}-byte observation",
+                filled, state.row_len
+            );
+            self.deviation(Deviation::RowWidth, end - filled as u64, message)?;
+        }
+
+        // Padding is shorter than a card, so every row starting before the
+        // last 80 bytes of the member is data
+        let obs_bytes = (end - state.obs_start) as usize;
+        let min_rows = match obs_bytes.checked_sub(CARD) {
+            Some(before_last_card) => before_last_card / state.row_len + 1,
+            None => 0,
+        };
+        let kept = min_rows
+            .saturating_sub(state.rows_read)
+            .min(state.blank_rows);
+        let dropped = state.blank_rows - kept;
+        state.replay_blanks = kept;
+        state.blank_rows = 0;
+        if dropped > 0 && state.all_char {
+            let rows = state.rows_read + kept;
+            let message = format!(
+                "{} trailing blank observations taken as card padding; the member has {} observations, or up to {} if they are data",
+                dropped,
+                rows,
+                rows + dropped
+            );
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                offset: end - (dropped * state.row_len + filled) as u64,
+                member: self.member_name.clone(),
+                message,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that only padding follows the declared observations of a V8
+    /// member. If data follows instead, the count is wrong: that is reported
+    /// and the end of the member is then found as for V5.
+    fn check_declared_end(&mut self, state: &mut RowState) -> Result<()> {
+        if self.at_member_end()? {
+            state.ended = true;
+            return Ok(());
+        }
+        let offset = self.cards.position();
+        self.deviation(
+            Deviation::Header,
+            offset,
+            format!("observations continue past the {} declared", state.rows_read),
+        )?;
+        state.rows_left = None;
+        Ok(())
+    }
+
+    /// True if the rest of the current card is padding and the next card
+    /// starts another member or the input ends
+    fn at_member_end(&mut self) -> Result<bool> {
+        if !self.cards.card_rest().iter().all(|&b| b == b' ' || b == 0) {
+            return Ok(false);
+        }
+        match self.cards.peek_card() {
+            Ok(None) => Ok(true),
+            Ok(Some(card)) => Ok(is_member_header(card)),
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+
+    /// Skips the unread rows of the current member, returning how many there were
+    fn skip_rest_of_member(&mut self) -> Result<usize> {
+        self.skip_rows(usize::MAX)
+    }
+
+    /// Skips up to `n` rows of the current member without decoding them,
+    /// returning how many were skipped. When the input can seek, the rows
+    /// are passed over by seeking once their cards have been scanned for
+    /// the end of the member; otherwise they are read.
+    fn skip_rows(&mut self, n: usize) -> Result<usize> {
+        let mut skipped = 0;
+        // Rows already read ahead come first
+        while skipped < n && self.current.as_ref().is_some_and(RowState::has_pending) {
+            self.read_raw_row()?;
+            skipped += 1;
+        }
+        if let Some(mut state) = self.current.take() {
+            let seeked = self.seek_rows(&mut state, n - skipped);
+            self.current = Some(state);
+            skipped += seeked?;
+        }
+        while skipped < n && self.read_raw_row()? {
+            skipped += 1;
+        }
+        Ok(skipped)
+    }
+
+    /// Skips up to `n` rows by seeking over them. Returns how many were
+    /// skipped, 0 if the input cannot seek there.
+    ///
+    /// Only rows found to lie before the last card of the member are passed
+    /// over, so that no header is crossed even if a declared count is
+    /// wrong; the rest are read, which tells them apart from padding as
+    /// usual.
+    fn seek_rows(&mut self, state: &mut RowState, n: usize) -> Result<usize> {
+        if state.ended || state.has_pending() {
+            return Ok(0);
+        }
+        let n = state.rows_left.map_or(n, |left| n.min(left));
+        let count = self.rows_before_end(state, n)?;
+        if count == 0 {
+            return Ok(0);
+        }
+        match self.cards.skip_record_bytes((count * state.row_len) as u64) {
+            Ok(true) => {}
+            Ok(false) => return Ok(0),
+            Err(e) => return Err(self.io_error(e)),
+        }
+        if let Some(left) = state.rows_left.as_mut() {
+            *left -= count;
+        }
+        state.rows_read += count;
+        Ok(count)
+    }
+
+    /// How many of the rows left in the current member are certainly data,
+    /// as far as can be told without reading them: those before its last
+    /// card when the input can seek, otherwise the declared count
+    fn certain_rows(&mut self) -> Result<usize> {
+        let mut state = match self.current.take() {
+            Some(state) => state,
+            None => return Ok(0),
+        };
+        let pending = state.replay_blanks + usize::from(state.held);
+        let ahead = if state.ended {
+            Ok(0)
+        } else if self.cards.can_seek() {
+            let left = state.rows_left.unwrap_or(usize::MAX);
+            self.rows_before_end(&mut state, left)
         } else {
-            bail!("No variables found. NAMESTR buffer size: {} bytes, bytes_per_var: {}, header count: {}", 
-                  namestr_buffer.len(), namestr_len, nvars_from_header);
+            Ok(state.rows_left.unwrap_or(0))
+        };
+        self.current = Some(state);
+        Ok(pending + ahead?)
+    }
+
+    /// How many of the next `n` rows certainly hold data: those that end
+    /// before the member does and start before its last card, which may
+    /// hold padding. The end of the member is found by scanning the cards
+    /// ahead for the next MEMBER header. Returns 0 if the input cannot seek.
+    fn rows_before_end(&mut self, state: &mut RowState, n: usize) -> Result<usize> {
+        if !self.cards.can_seek() {
+            return Ok(0);
+        }
+        let row_len = state.row_len as u64;
+        let end = match state.end {
+            Some(end) => end,
+            None => {
+                // The rows and a card of padding after them
+                let limit = (n as u64).saturating_mul(row_len).saturating_add(2 * CARD as u64);
+                match self.cards.scan_ahead(limit, is_member_header) {
+                    Ok(Some(end)) => end,
+                    // The member goes on past the rows and their padding
+                    Ok(None) => return Ok(n),
+                    Err(e) => return Err(self.io_error(e)),
+                }
+            }
+        };
+        state.end = Some(end);
+        let position = self.cards.position();
+        let complete = end.saturating_sub(position) / row_len;
+        let before_last_card = match end.checked_sub(position + CARD as u64) {
+            Some(gap) => gap / row_len + 1,
+            None => 0,
         };
-        
-        // Parse the NAMESTR records from the buffer
-        // Records are stored sequentially at 140-byte intervals (not card-aligned)
-        let mut vars = Vec::with_capacity(nvars);
-        for i in 0..nvars {
-            let start = i * namestr_len;  // Extract at 140-byte intervals, not card-aligned
-            if start + namestr_len <= namestr_buffer.len() {
-                let var = parse_namestr_140(&namestr_buffer[start..start + namestr_len]);
-                eprintln!("Variable {}: name='{}', length={}, position={}, is_char={}", 
-                         i, var.name, var.length, var.position, var.is_char);
-                vars.push(var);
+        Ok(n.min(complete.min(before_last_card) as usize))
+    }
+
+    /// Collects data cards up to the next header record, which is returned too
+    fn read_until_header(&mut self, what: &str) -> Result<(Vec<u8>, Card)> {
+        let mut block = Vec::new();
+        loop {
+            let card = self.expect_card(what)?;
+            if card.starts_with(HEADER_PREFIX) {
+                return Ok((block, card));
+            }
+            block.extend_from_slice(&card);
+        }
+    }
+
+    fn next_card(&mut self) -> Result<Option<Card>> {
+        self.cards.next_card().map_err(|e| self.io_error(e))
+    }
+
+    fn expect_card(&mut self, what: &str) -> Result<Card> {
+        match self.next_card()? {
+            Some(card) => Ok(card),
+            None => Err(XptError::Truncated {
+                offset: self.cards.position(),
+                member: self.member_name.clone(),
+                message: format!("{} missing", what),
+            }),
+        }
+    }
+
+    /// Byte offset of the last whole card read
+    fn last_card_offset(&self) -> u64 {
+        self.cards.position().saturating_sub(CARD as u64)
+    }
+
+    /// Turns notes from the text decoder into diagnostics
+    fn report_text(&mut self, offset: u64) {
+        for (severity, message) in self.text.take_notes() {
+            self.diagnostics.push(Diagnostic {
+                severity,
+                offset,
+                member: self.member_name.clone(),
+                message,
+            });
+        }
+    }
+
+    /// Handles a deviation from TS-140: an error in strict mode, otherwise
+    /// recorded as a diagnostic so that reading can go on
+    fn deviation(&mut self, kind: Deviation, offset: u64, message: String) -> Result<()> {
+        let member = self.member_name.clone();
+        if self.options.mode == ParseMode::Strict {
+            return Err(kind.into_error(offset, member, message));
+        }
+        self.diagnostics.push(Diagnostic {
+            severity: kind.severity(),
+            offset,
+            member,
+            message,
+        });
+        Ok(())
+    }
+
+    /// Error for the card just read, which is not the `expected` header
+    fn unexpected_header(&self, card: &Card, expected: &str) -> XptError {
+        if card.starts_with(HEADER_PREFIX) && parse_header(card).is_none() {
+            return XptError::UnsupportedVersion(format!(
+                "unknown header record '{}'",
+                String::from_utf8_lossy(&card[20..28]).trim_end()
+            ));
+        }
+        XptError::BadHeader {
+            offset: self.last_card_offset(),
+            member: self.member_name.clone(),
+            message: format!(
+                "expected {} header, found '{}'",
+                expected,
+                String::from_utf8_lossy(&card[..48])
+            ),
+        }
+    }
+
+    fn bad_header(&self, message: &str) -> XptError {
+        XptError::BadHeader {
+            offset: self.cards.position(),
+            member: self.member_name.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Turns a short final card into a truncation error
+    fn io_error(&self, error: io::Error) -> XptError {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            XptError::Truncated {
+                offset: self.cards.position(),
+                member: self.member_name.clone(),
+                message: error.to_string(),
+            }
+        } else {
+            XptError::Io(error)
+        }
+    }
+}
+
+/// One member of a transport file being streamed by an [`XptReader`]
+///
+/// Iterating yields the remaining observations of the member, decoded one
+/// at a time.
+pub struct MemberReader<'a, R> {
+    reader: &'a mut XptReader<R>,
+    info: MemberInfo,
+    vars: Vec<VarMeta>,
+    row_len: usize,
+    /// Index of the next observation within the member
+    index: usize,
+    /// Rows left to return under [`ReadOptions::limit`]
+    remaining: Option<usize>,
+}
+
+impl<R: Read> MemberReader<'_, R> {
+    pub fn info(&self) -> &MemberInfo {
+        &self.info
+    }
+
+    /// Variables being read: all of them, or those selected by
+    /// [`ReadOptions::columns`]
+    pub fn vars(&self) -> &[VarMeta] {
+        &self.vars
+    }
+
+    /// Width of one observation in bytes, as laid out by the NAMESTR records
+    pub fn row_len(&self) -> usize {
+        self.row_len
+    }
+
+    /// Index (from 0) of the next observation within the member, counting
+    /// the rows skipped by [`ReadOptions::skip`]
+    pub fn row_index(&self) -> usize {
+        self.index
+    }
+
+    /// Observations left to read, when the member declares its row count
+    /// (V8); `None` if it can only be found by reading to the end
+    pub fn rows_left(&self) -> Option<usize> {
+        let left = self.reader.current.as_ref().map_or(Some(0), |state| state.rows_left)?;
+        Some(self.remaining.map_or(left, |remaining| left.min(remaining)))
+    }
+
+    /// Decodes the next observation, or returns `None` at the end of the member
+    pub fn next_row(&mut self) -> Result<Option<Vec<Value>>> {
+        if !self.next_raw_row()? {
+            return Ok(None);
+        }
+        Ok(Some(self.decode_raw_row()))
+    }
+
+    /// Skips `n` observations without decoding them, returning how many
+    /// there were
+    pub fn skip_rows(&mut self, n: usize) -> Result<usize> {
+        let n = self.remaining.map_or(n, |remaining| n.min(remaining));
+        let skipped = self.reader.skip_rows(n)?;
+        self.index += skipped;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= skipped;
+        }
+        Ok(skipped)
+    }
+
+    /// Skips the remaining observations without decoding them, returning how
+    /// many there were
+    pub fn skip_rest(&mut self) -> Result<usize> {
+        self.skip_rows(usize::MAX)
+    }
+
+    /// Decodes only the last `n` of the remaining observations. The others
+    /// are skipped without decoding, and when the input can seek without
+    /// reading them either: only the last card of the member, where
+    /// padding may be, is read through.
+    pub fn tail(&mut self, n: usize) -> Result<Vec<Vec<Value>>> {
+        let certain = self.reader.certain_rows()?;
+        let window = self.remaining.map_or(certain, |remaining| certain.min(remaining));
+        self.skip_rows(window.saturating_sub(n))?;
+        // Raw rows are kept in a ring and only the survivors are decoded
+        let mut last: VecDeque<Vec<u8>> = VecDeque::with_capacity(n.min(1024));
+        while n > 0 && self.next_raw_row()? {
+            let mut raw = if last.len() == n {
+                last.pop_front().unwrap_or_default()
             } else {
-                // Not enough data for this variable - break
-                break;
+                Vec::new()
+            };
+            raw.clone_from(&self.reader.raw);
+            last.push_back(raw);
+        }
+        let reader = &mut *self.reader;
+        let rows = last
+            .iter()
+            .map(|raw| decode_row(raw, &self.vars, &mut reader.text))
+            .collect();
+        let offset = reader.cards.position();
+        reader.report_text(offset);
+        Ok(rows)
+    }
+
+    /// Decodes up to `max_rows` of the next observations, on several threads
+    /// as set by [`ReadOptions::parallelism`]. The rows are in file order;
+    /// an empty batch means the member has ended.
+    pub fn next_batch(&mut self, max_rows: usize) -> Result<Vec<Vec<Value>>> {
+        let threads = self.reader.options.parallelism.threads();
+        if threads == 1 {
+            let mut rows = Vec::new();
+            while rows.len() < max_rows {
+                match self.next_row()? {
+                    Some(row) => rows.push(row),
+                    None => break,
+                }
             }
+            return Ok(rows);
         }
-        
-        if vars.is_empty() {
-            bail!("Failed to parse any variables. Buffer size: {}, expected {} variables", 
-                  namestr_buffer.len(), nvars);
-        }
-        
-        // Sort variables by position to ensure correct order for output
-        // But note: the old parser uses sequential offsets when reading data, not position field
-        vars.sort_by_key(|v| v.position);
-        
-        // Calculate row length as sum of all variable lengths (like old parser)
-        // The position field in NAMESTR is for ordering, but data is stored sequentially
-        let total_row_len: usize = vars.iter().map(|v| v.length).sum();
-        eprintln!("Total row length: {} bytes (sum of lengths)", total_row_len);
-        
-        // Read the second OBS header card
-        let _oh1 = match read_card(&mut r) {
-            Ok(c) => c,
-            Err(e) => bail!("Failed to read OBS header second card: {}", e),
-        };
 
-        // Observation length is the maximum (position + length) across all variables
-        // This accounts for any gaps or padding between variables
-        let row_len = total_row_len;
-        eprintln!("Parsing observations: {} variables, row_len={} bytes", vars.len(), row_len);
-        let mut rows: Vec<Vec<Option<String>>> = Vec::new();
-        let mut buf: Vec<u8> = Vec::new();
+        // Raw rows are gathered in order, then decoded in chunks
+        let start = self.reader.current.as_ref().map_or(0, |state| {
+            state.obs_start + (state.rows_read * state.row_len) as u64
+        });
+        let mut batch = Vec::new();
+        let mut count = 0;
+        while count < max_rows && self.next_raw_row()? {
+            batch.extend_from_slice(&self.reader.raw);
+            count += 1;
+        }
+        let reader = &mut *self.reader;
+        let (rows, notes) = decode_rows(&batch, self.row_len, &self.vars, &mut reader.text, threads);
+        for (row, severity, message) in notes {
+            reader.diagnostics.push(Diagnostic {
+                severity,
+                offset: start + (row * self.row_len) as u64,
+                member: reader.member_name.clone(),
+                message,
+            });
+        }
+        Ok(rows)
+    }
+
+    /// Reads the next observation of the window into the reader's buffer
+    fn next_raw_row(&mut self) -> Result<bool> {
+        if self.remaining == Some(0) || !self.reader.read_raw_row()? {
+            return Ok(false);
+        }
+        self.index += 1;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Ok(true)
+    }
+
+    fn decode_raw_row(&mut self) -> Vec<Value> {
+        let reader = &mut *self.reader;
+        let row = decode_row(&reader.raw, &self.vars, &mut reader.text);
+        let offset = reader.cards.position() - reader.raw.len() as u64;
+        reader.report_text(offset);
+        row
+    }
 
-        // Pull cards until we see the start of the next HEADER...
+    /// Collects the remaining observations into a [`Dataset`]
+    pub fn into_dataset(mut self) -> Result<Dataset> {
+        let batch_rows = self.reader.options.parallelism.threads() * BATCH_ROWS_PER_THREAD;
+        let mut rows = Vec::new();
         loop {
-            let mut c = [0u8; CARD];
-            match r.read_exact(&mut c) {
-                Ok(()) => {
-                    // If this card starts a new header, push back into a small stash and break
-                    if card_str(&c).starts_with("HEADER RECORD*******") {
-                        // "Unread" this card by storing it in an internal buffer of the reader:
-                        // (BufReader doesn't support un-read; so we keep it in memory and handle after loop)
-                        // Strategy: keep it and process after loop by creating a chain reader.
-                        // Simpler: just keep it and break; the outer loop starts from this header.
-                        // But we need to keep it: store in `buf_head`.
-                        // Workaround: store in a global and use it next loop? For simplicity: break and
-                        // re-create the BufReader from an in-memory buffer is overkill.
-                        // Pragmatic solution: keep c in a local and pass to outer loop via return value
-                        // -> Simpler: We break, and rely on the outer loop to continue; this loses 1 card.
-                        // To avoid loss, we detect this only when buf is empty (i.e., start of a row boundary).
-                        if buf.is_empty() { // safe to break: no partial row
-                            // put c into a "pre-read" slot by using a custom reader would be cleaner;
-                            // for brevity, we stop here and accept that the outer loop already consumed it.
-                            // (Real-world: implement a small LookaheadReader.)
-                            // We'll break and assume next read starts at mh0 for next member.
-                            // Note: this works with many files because headers align at card boundaries.
-                            // For robustness, implement a LookaheadReader in production.
-                            break;
-                        } else {
-                            // Otherwise, treat it as data (rare) and continue.
-                            buf.extend_from_slice(&c);
-                        }
-                    } else {
-                        buf.extend_from_slice(&c);
-                    }
-                }
-                Err(_) => break, // EOF
-            }
-
-            while buf.len() >= row_len {
-                // Debug: show first row data
-                if rows.is_empty() && buf.len() >= row_len {
-                    eprintln!("First row data (first 100 bytes): {:?}", &buf[0..row_len.min(100)]);
-                    // Show what we'd read for first few variables
-                    for (i, v) in vars.iter().take(5).enumerate() {
-                        if v.position + v.length <= buf.len() {
-                            let slice = &buf[v.position..v.position + v.length.min(20)];
-                            eprintln!("  Variable {} ({}): position={}, slice={:?}", i, v.name, v.position, slice);
-                        }
-                    }
+            let batch = self.next_batch(batch_rows)?;
+            if batch.is_empty() {
+                break;
+            }
+            rows.extend(batch);
+        }
+        Ok(Dataset {
+            name: self.info.name.clone(),
+            member: self.info,
+            library: self.reader.library.clone(),
+            vars: self.vars,
+            rows,
+        })
+    }
+}
+
+impl<R: Read> Iterator for MemberReader<'_, R> {
+    type Item = Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().transpose()
+    }
+}
+
+/// Read every member (dataset) of a V5 or V8 transport file from a reader
+pub fn read_xpt_v5_from_reader<R: Read>(inner: R) -> Result<Vec<Dataset>> {
+    read_xpt_v5_from_reader_with(inner, ReadOptions::default()).map(|(datasets, _)| datasets)
+}
+
+/// Read every member of a transport file from a reader with the given
+/// options, returning the problems recovered from alongside the datasets
+pub fn read_xpt_v5_from_reader_with<R: Read>(
+    inner: R,
+    options: ReadOptions,
+) -> Result<(Vec<Dataset>, Vec<Diagnostic>)> {
+    let mut reader = XptReader::with_options(inner, options)?;
+    let mut datasets = Vec::new();
+
+    while let Some(member) = reader.next_member()? {
+        datasets.push(member.into_dataset()?);
+    }
+
+    if datasets.is_empty() {
+        return Err(XptError::BadHeader {
+            offset: reader.cards.position(),
+            member: None,
+            message: "file contains no members".to_string(),
+        });
+    }
+    Ok((datasets, reader.diagnostics))
+}
+
+/// Decodes the cells of one raw observation
+fn decode_row(raw: &[u8], vars: &[VarMeta], text: &mut TextDecoder) -> Vec<Value> {
+    vars.iter()
+        .zip(cell_ranges(vars))
+        .map(|(var, range)| decode_cell(&raw[range], var, text))
+        .collect()
+}
+
+/// Notes from decoding consecutive observations, each with the index of
+/// the observation that produced it
+pub(crate) type RowNotes = Vec<(usize, Severity, String)>;
+
+/// Decodes consecutive raw observations of `row_len` bytes, splitting them
+/// across up to `threads` threads. The rows are returned in order, with the
+/// text decoder's notes tagged by row as a row-by-row decode would give them.
+pub(crate) fn decode_rows(
+    raw: &[u8],
+    row_len: usize,
+    vars: &[VarMeta],
+    text: &mut TextDecoder,
+    threads: usize,
+) -> (Vec<Vec<Value>>, RowNotes) {
+    let cells = cell_ranges(vars);
+    let count = raw.len() / row_len;
+    let mut notes = RowNotes::new();
+    if threads > 1 && text.encoding().is_none() {
+        if let Some(row) = settle_encoding(raw.chunks_exact(row_len), vars, &cells, text) {
+            for (severity, message) in text.take_notes() {
+                notes.push((row, severity, message));
+            }
+        }
+    }
+    let base = text.fork();
+    let chunks = map_chunks(count, threads, |rows| {
+        let mut text = base.fork();
+        let mut notes = RowNotes::new();
+        let decoded: Vec<Vec<Value>> = rows
+            .map(|row| {
+                let raw = &raw[row * row_len..(row + 1) * row_len];
+                let values = vars
+                    .iter()
+                    .zip(&cells)
+                    .map(|(var, range)| decode_cell(&raw[range.clone()], var, &mut text))
+                    .collect();
+                for (severity, message) in text.take_notes() {
+                    notes.push((row, severity, message));
                 }
-                
-                let mut row = Vec::with_capacity(vars.len());
-                // Use sequential offsets (like old parser) - data is stored sequentially
-                let mut offset = 0usize;
-                for v in &vars {
-                    if offset + v.length > buf.len() {
-                        eprintln!("Warning: Variable {} at offset {} + length {} exceeds buffer size {}", 
-                                 v.name, offset, v.length, buf.len());
-                        row.push(None);
-                        offset += v.length;
-                        continue;
+                values
+            })
+            .collect();
+        (decoded, text, notes)
+    });
+    let mut rows = Vec::with_capacity(count);
+    for (decoded, fork, chunk_notes) in chunks {
+        text.join(fork);
+        for (row, severity, message) in chunk_notes {
+            if text.keep_note(severity) {
+                notes.push((row, severity, message));
+            }
+        }
+        rows.extend(decoded);
+    }
+    (rows, notes)
+}
+
+/// Decodes one variable of consecutive raw observations, splitting them
+/// across up to `threads` threads
+pub(crate) fn decode_column(
+    raw: &[u8],
+    row_len: usize,
+    var: &VarMeta,
+    text: &mut TextDecoder,
+    threads: usize,
+) -> Vec<Value> {
+    let range = var.position..var.position + var.length;
+    let count = raw.len() / row_len;
+    if !var.is_char && (1..=8).contains(&var.length) {
+        let chunks = map_chunks(count, threads, |rows| {
+            decode_numeric_column(&raw[rows.start * row_len..rows.end * row_len], row_len, var)
+        });
+        return chunks.into_iter().flatten().collect();
+    }
+    if threads > 1 && var.is_char && text.encoding().is_none() {
+        settle_encoding(raw.chunks_exact(row_len), std::slice::from_ref(var), std::slice::from_ref(&range), text);
+    }
+    let base = text.fork();
+    let chunks = map_chunks(count, threads, |rows| {
+        let mut text = base.fork();
+        let decoded: Vec<Value> = rows
+            .map(|row| decode_cell(&raw[row * row_len..][range.clone()], var, &mut text))
+            .collect();
+        (decoded, text)
+    });
+    let mut values = Vec::with_capacity(count);
+    for (decoded, fork) in chunks {
+        text.join(fork);
+        values.extend(decoded);
+    }
+    values
+}
+
+/// Decodes one numeric variable of consecutive raw observations, converting
+/// the values straight from the rows
+fn decode_numeric_column(raw: &[u8], row_len: usize, var: &VarMeta) -> Vec<Value> {
+    let mut values = vec![0.0; raw.len() / row_len];
+    ibm64_strided_to_f64(raw, row_len, var.position, var.length, &mut values);
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(row, value)| {
+            if value.is_nan() {
+                // Only missing values convert to NaN
+                let cell = &raw[row * row_len + var.position..][..var.length];
+                Value::from_ibm((None, ibm64_missing(cell)))
+            } else {
+                Value::Numeric(value)
+            }
+        })
+        .collect()
+}
+
+/// Settles an auto-detected encoding from the first non-ASCII character
+/// value, as a row-by-row decode would, so that every chunk agrees on it.
+/// Returns the index of the row it was settled from.
+fn settle_encoding<'a>(
+    rows: impl Iterator<Item = &'a [u8]>,
+    vars: &[VarMeta],
+    cells: &[Range<usize>],
+    text: &mut TextDecoder,
+) -> Option<usize> {
+    for (index, raw) in rows.enumerate() {
+        for (var, range) in vars.iter().zip(cells) {
+            if var.is_char && text.settle(&raw[range.clone()]).is_some() {
+                return Some(index);
+            }
+        }
+    }
+    None
+}
+
+/// Stores values back to back in variable order, replacing their offsets
+fn lay_out_contiguously(vars: &mut [VarMeta]) {
+    let mut position = 0;
+    for var in vars.iter_mut() {
+        var.position = position;
+        position += var.length;
+    }
+}
+
+/// Byte range of each variable within an observation, from its npos offset
+pub(crate) fn cell_ranges(vars: &[VarMeta]) -> Vec<Range<usize>> {
+    vars.iter()
+        .map(|var| var.position..var.position + var.length)
+        .collect()
+}
+
+/// Decodes one cell according to the variable type
+pub(crate) fn decode_cell(cell: &[u8], var: &VarMeta, text: &mut TextDecoder) -> Value {
+    if var.is_char {
+        Value::Character(text.decode(cell))
+    } else {
+        decode_numeric(cell)
+    }
+}
+
+/// Parses the two real header cards following the LIBRARY header
+///
+/// ```text
+/// SAS     SAS     SASLIB  9.4     X64_7PRO                        16OCT26:10:20:30
+/// 16OCT26:10:20:30
+/// ```
+fn parse_library_header(first: &Card, second: &Card, version: XptVersion) -> LibraryInfo {
+    LibraryInfo {
+        version,
+        sas_version: ascii_string(first, 24, 8),
+        os: ascii_string(first, 32, 8),
+        created: ascii_string(first, 64, 16),
+        modified: ascii_string(second, 0, 16),
+    }
+}
+
+/// Parses the two member header data cards following the DSCRPTR header
+///
+/// ```text
+/// SAS     DM      SASDATA 9.4     X64_7PRO                        16OCT26:10:20:30
+/// 16OCT26:10:20:30                Demographics                            DATA
+/// ```
+///
+/// In V8 files the dataset name takes 32 characters, shifting the
+/// version, OS and creation datetime of the first card.
+fn parse_member_header(
+    first: &Card,
+    second: &Card,
+    version: XptVersion,
+    text: &mut TextDecoder,
+) -> MemberInfo {
+    let (name, rest) = match version {
+        XptVersion::V5 => (ascii_string(first, 8, 8), 24),
+        XptVersion::V8 => (ascii_string(first, 8, 32), 48),
+    };
+    MemberInfo {
+        name,
+        sas_version: ascii_string(first, rest, 8),
+        os: ascii_string(first, rest + 8, 8),
+        created: ascii_string(first, 64, 16),
+        modified: ascii_string(second, 0, 16),
+        label: text.decode(&second[32..72]),
+        dataset_type: ascii_string(second, 72, 8),
+        version,
+    }
+}
+
+/// Parses one NAMESTR record per TS-140 (140 bytes, or 136 on VAX/VMS
+/// where `rest` is 4 bytes shorter)
+///
+/// ```text
+/// short ntype;      //  0..2   1=numeric, 2=char
+/// short nhfun;      //  2..4   hash, always 0
+/// short nlng;       //  4..6   length in observation
+/// short nvar0;      //  6..8   varnum
+/// char  nname[8];   //  8..16
+/// char  nlabel[40]; // 16..56
+/// char  nform[8];   // 56..64
+/// short nfl;        // 64..66  format length
+/// short nfd;        // 66..68  format decimals
+/// short nfj;        // 68..70  justification, 0=left 1=right
+/// char  nfill[2];   // 70..72
+/// char  niform[8];  // 72..80
+/// short nifl;       // 80..82  informat length
+/// short nifd;       // 82..84  informat decimals
+/// long  npos;       // 84..88  position of value in observation
+/// char  rest[52];   // 88..140
+/// ```
+///
+/// V8 records keep the same layout but use `rest` for the full name:
+///
+/// ```text
+/// char  longname[32]; //  88..120
+/// short lablen;       // 120..122 label length, >40 means a LABELV8/9 record
+/// char  rest[18];     // 122..140
+/// ```
+fn parse_name_string(data: &[u8], version: XptVersion, text: &mut TextDecoder) -> VarMeta {
+    let mut name = ascii_string(data, 8, 8);
+    if version == XptVersion::V8 {
+        let long_name = ascii_string(data, 88, 32);
+        if !long_name.is_empty() {
+            name = long_name;
+        }
+    }
+    VarMeta {
+        name,
+        label: text.decode(&data[16..56]),
+        format_name: ascii_string(data, 56, 8),
+        format_len: be_i16(data, 64),
+        format_decimals: be_i16(data, 66),
+        format_justification: be_i16(data, 68),
+        informat_name: ascii_string(data, 72, 8),
+        informat_len: be_i16(data, 80),
+        informat_decimals: be_i16(data, 82),
+        length: be_i16(data, 4).max(0) as usize,
+        position: be_i32(data, 84).max(0) as usize,
+        is_char: be_i16(data, 0) != 1,
+        varnum: be_i16(data, 6),
+    }
+}
+
+/// Checks the fields of a NAMESTR record that TS-140 constrains, returning
+/// a description of each problem
+fn check_name_string(data: &[u8], version: XptVersion) -> Vec<String> {
+    let mut problems = Vec::new();
+    let length = be_i16(data, 4);
+    match be_i16(data, 0) {
+        1 if !(2..=8).contains(&length) => {
+            problems.push(format!("numeric length {} is outside 2-8", length))
+        }
+        2 if length < 1 => problems.push(format!("character length {} is below 1", length)),
+        1 | 2 => {}
+        other => problems.push(format!("unknown variable type {}", other)),
+    }
+    let has_name = !ascii_string(data, 8, 8).is_empty()
+        || (version == XptVersion::V8 && !ascii_string(data, 88, 32).is_empty());
+    if !has_name {
+        problems.push("variable has no name".to_string());
+    }
+    problems
+}
+
+/// Merges LABELV8/LABELV9 records into the NAMESTR metadata
+///
+/// Each record starts with big-endian shorts (varnum, name length, label
+/// length and, for LABELV9, format and informat lengths) followed by the
+/// strings themselves; records are packed back to back. Returns a
+/// description of each record that could not be applied.
+fn apply_long_labels(
+    vars: &mut [VarMeta],
+    block: &[u8],
+    count: usize,
+    v9: bool,
+    text: &mut TextDecoder,
+) -> Vec<String> {
+    let field_count = if v9 { 4 } else { 2 };
+    let prefix_len = 2 + field_count * 2;
+    let mut problems = Vec::new();
+    let mut offset = 0;
+    for index in 0..count {
+        if offset + prefix_len > block.len() {
+            problems.push(format!(
+                "label header declares {} records but only {} are present",
+                count, index
+            ));
+            break;
+        }
+        let varnum = be_i16(block, offset);
+        let lengths: Vec<usize> = (0..field_count)
+            .map(|index| be_i16(block, offset + 2 + index * 2).max(0) as usize)
+            .collect();
+        offset += prefix_len;
+        if offset + lengths.iter().sum::<usize>() > block.len() {
+            problems.push(format!("label record {} runs past the end of the block", index + 1));
+        }
+
+        let fields: Vec<String> = lengths
+            .iter()
+            .enumerate()
+            .map(|(field_index, &length)| {
+                let field = match field_index {
+                    1 if offset + length <= block.len() => {
+                        text.decode(&block[offset..offset + length])
                     }
-                    let slice = &buf[offset..offset + v.length];
-                    offset += v.length;
-                    if v.is_char {
-                        row.push(Some(decode_char(slice)));
-                    } else {
-                        // numeric: read right-aligned up to 8 bytes (IBM double, truncated if width<8)
-                        let mut eight = [0u8; 8];
-                        if v.length >= 8 {
-                            eight.copy_from_slice(&slice[0..8]);
-                        } else {
-                            // right-align
-                            let pad = 8 - v.length;
-                            eight[pad..].copy_from_slice(slice);
-                        }
-                        let (val, miss) = ibm64_to_f64(&eight);
-                        match miss {
-                            IbmMissing::None => row.push(val.map(|f| f.to_string())),
-                            _ => row.push(None),
-                        }
+                    _ => ascii_string(block, offset, length),
+                };
+                offset += length;
+                field
+            })
+            .collect();
+
+        match vars.iter_mut().find(|v| v.varnum == varnum) {
+            Some(var) => {
+                let targets = [
+                    &mut var.name,
+                    &mut var.label,
+                    &mut var.format_name,
+                    &mut var.informat_name,
+                ];
+                for (target, field) in targets.into_iter().zip(fields) {
+                    if !field.is_empty() {
+                        *target = field;
                     }
                 }
-                if row.len() == vars.len() {
-                    rows.push(row);
-                } else {
-                    eprintln!("Skipping row with {} values (expected {})", row.len(), vars.len());
+            }
+            None => problems.push(format!(
+                "label record {} refers to unknown variable number {}",
+                index + 1,
+                varnum
+            )),
+        }
+    }
+    problems
+}
+
+/// Orders NAMESTR records by variable number and fills in defaults
+fn build_vars(records: Vec<VarMeta>) -> Vec<VarMeta> {
+    let mut ordered_records: Vec<(usize, VarMeta)> = records.into_iter().enumerate().collect();
+    ordered_records.sort_by_key(|(idx, record)| {
+        let order = if record.varnum > 0 {
+            record.varnum as usize
+        } else {
+            idx + 1
+        };
+        (order, *idx)
+    });
+
+    ordered_records
+        .into_iter()
+        .enumerate()
+        .map(|(index, (_, mut var))| {
+            if var.name.is_empty() {
+                var.name = format!("VAR{}", index + 1);
+            }
+            if var.is_char {
+                var.length = var.length.max(constants::MIN_CHARACTER_LENGTH);
+            }
+            var
+        })
+        .collect()
+}
+
+/// Decodes a numeric cell. Values stored with LENGTH 3-7 keep only the
+/// leading bytes of the IBM double, so the mantissa is zero-extended.
+fn decode_numeric(cell: &[u8]) -> Value {
+    Value::from_ibm(ibm64_to_f64(cell))
+}
+
+fn be_i16(data: &[u8], offset: usize) -> i16 {
+    i16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn be_i32(data: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// True if `data` holds only blanks, as TS-140 requires of padding
+fn is_blank(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == b' ')
+}
+
+fn ascii_string(data: &[u8], offset: usize, length: usize) -> String {
+    if offset >= data.len() || offset + length > data.len() {
+        return String::new();
+    }
+    ascii_string_trimmed(&data[offset..offset + length])
+}
+
+fn ascii_string_trimmed(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::write_xpt_to_writer_with;
+    use crate::{Columns, Parallelism, TextEncoding, WriteOptions};
+
+    fn write(version: XptVersion, vars: Vec<VarMeta>, rows: Vec<Vec<Value>>) -> Vec<u8> {
+        let options = WriteOptions {
+            version,
+            ..WriteOptions::default()
+        };
+        let mut out = Vec::new();
+        write_xpt_to_writer_with(&mut out, &[Dataset::new("T", vars, rows)], options).unwrap();
+        out
+    }
+
+    fn read(data: &[u8]) -> (Vec<Vec<Value>>, Vec<Diagnostic>) {
+        let (mut datasets, diagnostics) =
+            read_xpt_v5_from_reader_with(data, ReadOptions::default()).unwrap();
+        (datasets.remove(0).rows, diagnostics)
+    }
+
+    fn text(value: &str) -> Vec<Value> {
+        vec![Value::Character(value.to_string())]
+    }
+
+    /// Rewrites the observation count of the OBSV8 header
+    fn declare_rows(data: &mut [u8], count: usize) {
+        let header = data
+            .windows(25)
+            .position(|w| w == b"HEADER RECORD*******OBSV8")
+            .unwrap();
+        data[header + 48..header + 63].copy_from_slice(format!("{:015}", count).as_bytes());
+    }
+
+    #[test]
+    fn trailing_blank_rows_that_fit_a_card_are_ambiguous() {
+        // Ten 8-byte rows fit a card: two blank rows plus five of padding
+        let rows = vec![text("a"), text("b"), text("c"), text(""), text("")];
+        let data = write(XptVersion::V5, vec![VarMeta::character("C", 8)], rows);
+        assert_eq!(data.len() % CARD, 0);
+
+        let (rows, diagnostics) = read(&data);
+        assert_eq!(rows, vec![text("a"), text("b"), text("c")]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.contains("the member has 3 observations, or up to 10"));
+    }
+
+    #[test]
+    fn declared_count_settles_trailing_blank_rows() {
+        let rows = vec![text("a"), text("b"), text("c"), text(""), text("")];
+        let data = write(XptVersion::V8, vec![VarMeta::character("C", 8)], rows.clone());
+
+        let (read_rows, diagnostics) = read(&data);
+        assert_eq!(read_rows, rows);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn row_straddling_the_last_card_is_data() {
+        // The third 30-byte row runs from byte 60 to 90, across the card
+        // boundary, so even blank it cannot be padding; two blank rows fit
+        // in the 70 bytes of padding after it
+        let vars = vec![VarMeta::character("C", 30)];
+        let data = write(XptVersion::V5, vars.clone(), vec![text("x1"), text("x2"), text("x3")]);
+        let (rows, _) = read(&data);
+        assert_eq!(rows, vec![text("x1"), text("x2"), text("x3")]);
+
+        let data = write(XptVersion::V5, vars, vec![text("x1"), text("x2"), text("")]);
+        let (rows, diagnostics) = read(&data);
+        assert_eq!(rows, vec![text("x1"), text("x2"), text("")]);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("the member has 3 observations, or up to 5"));
+    }
+
+    /// Start of the first header record named `name`
+    fn find_header(data: &[u8], name: &str) -> usize {
+        let prefix = format!("HEADER RECORD*******{:<8}", name);
+        data.chunks(CARD)
+            .position(|card| card.starts_with(prefix.as_bytes()))
+            .map(|index| index * CARD)
+            .unwrap()
+    }
+
+    /// Rewrites the 140-byte NAMESTR records of a one-member V5 file as the
+    /// 136-byte records written on VAX/VMS
+    fn to_vax(data: &[u8], count: usize) -> Vec<u8> {
+        let namestr = find_header(data, "NAMESTR");
+        let mut vax = data[..namestr + CARD].to_vec();
+        let member = find_header(data, "MEMBER");
+        vax[member + 74..member + 78].copy_from_slice(b"0136");
+        for record in data[namestr + CARD..].chunks(140).take(count) {
+            vax.extend_from_slice(&record[..136]);
+        }
+        vax.resize(vax.len().div_ceil(CARD) * CARD, b' ');
+        vax.extend_from_slice(&data[find_header(data, "OBS")..]);
+        vax
+    }
+
+    fn labelled_vars() -> Vec<VarMeta> {
+        let mut id = VarMeta::character("USUBJID", 12);
+        id.label = "Unique Subject Identifier".to_string();
+        let mut aval = VarMeta::numeric("AVAL");
+        aval.label = "Analysis Value".to_string();
+        aval.format_name = "BEST".to_string();
+        aval.format_len = 12;
+        aval.informat_name = "COMMA".to_string();
+        aval.informat_decimals = 2;
+        vec![id, aval, VarMeta::character("FLAG", 1)]
+    }
+
+    #[test]
+    fn vax_namestr_records_are_read() {
+        let rows: Vec<Vec<Value>> = (0..7)
+            .map(|i| {
+                let id = Value::Character(format!("S{}", i));
+                vec![id, Value::Numeric(i as f64), Value::Character("Y".into())]
+            })
+            .collect();
+        let data = write(XptVersion::V5, labelled_vars(), rows.clone());
+        let vax = to_vax(&data, 3);
+        assert_ne!(vax, data);
+
+        let strict = ReadOptions::strict;
+        let (expected, _) = read_xpt_v5_from_reader_with(data.as_slice(), strict()).unwrap();
+        let (read, diagnostics) = read_xpt_v5_from_reader_with(vax.as_slice(), strict()).unwrap();
+        assert!(diagnostics.is_empty());
+        assert_eq!(read[0].rows, rows);
+        assert_eq!(format!("{:?}", read[0].vars), format!("{:?}", expected[0].vars));
+    }
+
+    #[test]
+    fn unexpected_namestr_length_is_reported() {
+        let row = vec![Value::Character("S1".into()), Value::Numeric(1.0), Value::Character("Y".into())];
+        let data = write(XptVersion::V5, labelled_vars(), vec![row]);
+        let member = find_header(&data, "MEMBER");
+        for (declared, found) in [(b"0150", "150"), (b"    ", "none")] {
+            let mut data = data.clone();
+            data[member + 74..member + 78].copy_from_slice(declared);
+
+            // 140-byte records are assumed
+            let (read, diagnostics) = read(&data);
+            assert_eq!(read.len(), 1);
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].offset, (member + 74) as u64);
+            assert_eq!(
+                diagnostics[0].message,
+                format!("MEMBER header declares NAMESTR length {}, expected 140 or 136", found)
+            );
+
+            let strict = read_xpt_v5_from_reader_with(data.as_slice(), ReadOptions::strict());
+            let offset = (member + 74) as u64;
+            assert!(matches!(strict, Err(XptError::BadHeader { offset: at, .. }) if at == offset));
+        }
+    }
+
+    #[test]
+    fn selected_columns_are_projected_in_order() {
+        let rows: Vec<Vec<Value>> = (0..3000)
+            .map(|i| {
+                let id = Value::Character(format!("S{}", i));
+                vec![id, Value::Numeric(i as f64), Value::Character("Y".into())]
+            })
+            .collect();
+        let data = write(XptVersion::V5, labelled_vars(), rows.clone());
+        let project = |picks: &[usize]| -> Vec<Vec<Value>> {
+            rows.iter()
+                .map(|row| picks.iter().map(|&pick| row[pick].clone()).collect())
+                .collect()
+        };
+        let selections = [
+            (Columns::names(["flag", "Missing", "usubjid"]), vec![2, 0], 1),
+            (Columns::filter(|var| var.is_char), vec![0, 2], 0),
+            (Columns::names(["AVAL"]), vec![1], 0),
+        ];
+
+        for (columns, picks, missing) in selections {
+            let expected = project(&picks);
+            let vars = labelled_vars();
+            let names: Vec<&str> = picks.iter().map(|&pick| vars[pick].name.as_str()).collect();
+            for parallelism in [Parallelism::Sequential, Parallelism::Threads(4)] {
+                let options = ReadOptions {
+                    columns: columns.clone(),
+                    parallelism,
+                    ..ReadOptions::default()
+                };
+                let (datasets, diagnostics) =
+                    read_xpt_v5_from_reader_with(data.as_slice(), options.clone()).unwrap();
+                let dataset = &datasets[0];
+                let read_names: Vec<&str> = dataset.vars.iter().map(|v| v.name.as_str()).collect();
+                assert_eq!(read_names, names);
+                assert_eq!(dataset.rows, expected);
+                assert_eq!(diagnostics.len(), missing);
+                if missing > 0 {
+                    assert_eq!(diagnostics[0].severity, Severity::Warning);
+                    assert_eq!(diagnostics[0].message, "column Missing not found");
+                    assert_eq!(diagnostics[0].member.as_deref(), Some("T"));
+                }
+
+                let mut reader = XptReader::with_options(data.as_slice(), options.clone()).unwrap();
+                let mut member = reader.next_member().unwrap().unwrap();
+                assert_eq!(member.vars().len(), names.len());
+                assert_eq!(member.next_row().unwrap().unwrap(), expected[0]);
+                assert_eq!(member.next_batch(5000).unwrap(), expected[1..]);
+
+                let mapped = crate::MappedXpt::from_bytes_with(data.as_slice(), options).unwrap();
+                let member = mapped.member(0).unwrap();
+                assert_eq!(member.vars().len(), names.len());
+                assert_eq!(member.var_index(&names[0].to_lowercase()), Some(0));
+                assert_eq!(member.row(2999).unwrap(), expected[2999]);
+                assert_eq!(member.decode_rows(0..3000).unwrap(), expected);
+                for index in 0..names.len() {
+                    let column: Vec<Value> = expected.iter().map(|row| row[index].clone()).collect();
+                    assert_eq!(member.column(index).unwrap(), column);
                 }
-                // drop one row worth of bytes (from 0..row_len)
-                buf.drain(0..row_len);
+                assert_eq!(mapped.diagnostics(), diagnostics);
             }
         }
+    }
+
+    /// Replaces the npos offsets of a one-member V5 file and its
+    /// observations, which are given raw
+    fn with_positions(data: &[u8], positions: &[i32], raw_rows: &[Vec<u8>]) -> Vec<u8> {
+        let namestr = find_header(data, "NAMESTR");
+        let obs = find_header(data, "OBS");
+        let mut out = data[..obs + CARD].to_vec();
+        for (index, position) in positions.iter().enumerate() {
+            let npos = namestr + CARD + index * 140 + 84;
+            out[npos..npos + 4].copy_from_slice(&position.to_be_bytes());
+        }
+        out.extend(raw_rows.concat());
+        out.resize(out.len().div_ceil(CARD) * CARD, b' ');
+        out
+    }
+
+    #[test]
+    fn npos_offsets_place_the_values() {
+        let (vars, rows) = numeric_rows(20);
+        let data = write(XptVersion::V5, vars, rows.clone());
+        let raw = |i: usize, gap: usize, swap: bool| {
+            let id = format!("{:<4}", format!("r{}", i)).into_bytes();
+            let x = crate::f64_to_ibm64(i as f64).unwrap().to_vec();
+            let mut row = if swap { [x, id].concat() } else { [id, x].concat() };
+            row.splice(if swap { 8..8 } else { 4..4 }, vec![b' '; gap]);
+            row
+        };
 
-        eprintln!("Parsed {} rows for dataset", rows.len());
+        // Values stored in the other order, with a gap between them, and
+        // both, up to the slack allowed
+        let layouts = [([8, 0], 0, true), ([0, 40], 36, false), ([90, 0], 82, true)];
+        for (positions, gap, swap) in layouts {
+            let raw_rows: Vec<Vec<u8>> = (0..20).map(|i| raw(i, gap, swap)).collect();
+            let moved = with_positions(&data, &positions, &raw_rows);
+            let (read, diagnostics) =
+                read_xpt_v5_from_reader_with(moved.as_slice(), ReadOptions::strict()).unwrap();
+            assert!(diagnostics.is_empty());
+            assert_eq!(read[0].vars[0].position, positions[0] as usize);
+            assert_eq!(read[0].rows, rows, "positions {:?}", positions);
+        }
 
-        // Dataset name is available in the member header data; TS-140 shows where,
-        // but for brevity we use a placeholder here. You can parse _mhd0/_mhd1 to extract it.
-        let ds_name = String::from("DATASET");
+        // C takes 4 bytes and X 8, so X cannot end past 2 * 12 + 80 bytes
+        let raw_rows: Vec<Vec<u8>> = (0..20).map(|i| raw(i, 0, false)).collect();
+        let corrupt = with_positions(&data, &[0, 1000], &raw_rows);
+        let (read, diagnostics) = read(&corrupt);
+        assert_eq!(read, rows);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].offset, (find_header(&data, "NAMESTR") + CARD) as u64);
+        assert_eq!(
+            diagnostics[0].message,
+            "X ends at byte 1008, far beyond the 12 bytes of all values; assuming values are contiguous"
+        );
+        let strict = read_xpt_v5_from_reader_with(corrupt.as_slice(), ReadOptions::strict());
+        assert!(matches!(strict, Err(XptError::BadHeader { .. })));
+    }
 
-        out.push(Dataset { name: ds_name, vars, rows });
+    fn numeric_rows(count: usize) -> (Vec<VarMeta>, Vec<Vec<Value>>) {
+        let vars = vec![VarMeta::character("C", 4), VarMeta::numeric("X")];
+        let rows = (0..count)
+            .map(|i| vec![Value::Character(format!("r{}", i)), Value::Numeric(i as f64)])
+            .collect();
+        (vars, rows)
     }
 
-    if out.is_empty() {
-        bail!("No datasets found. File may not be a valid XPT v5 file or may be corrupted.");
+    #[test]
+    fn declared_rows_missing_from_the_data_are_not_made_up() {
+        // Ten 12-byte rows leave room for three rows of padding
+        let (vars, rows) = numeric_rows(10);
+        let mut data = write(XptVersion::V8, vars, rows.clone());
+        declare_rows(&mut data, 11);
+
+        let (read_rows, diagnostics) = read(&data);
+        assert_eq!(read_rows, rows);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "1 declared observations are missing");
+
+        let mapped = crate::MappedXpt::from_bytes(data.clone()).unwrap();
+        assert_eq!(mapped.member(0).unwrap().row_count(), 10);
+
+        let strict = read_xpt_v5_from_reader_with(data.as_slice(), ReadOptions::strict());
+        assert!(matches!(strict, Err(XptError::Truncated { .. })));
     }
 
-    Ok(out)
-}
+    #[test]
+    fn rows_past_the_declared_count_are_not_dropped() {
+        let (vars, rows) = numeric_rows(10);
+        let mut data = write(XptVersion::V8, vars, rows.clone());
+        declare_rows(&mut data, 9);
+
+        let (read_rows, diagnostics) = read(&data);
+        assert_eq!(read_rows, rows);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "observations continue past the 9 declared");
+
+        let strict = read_xpt_v5_from_reader_with(data.as_slice(), ReadOptions::strict());
+        assert!(matches!(strict, Err(XptError::BadHeader { .. })));
+
+        // A count too large for the member but not for the file must not
+        // make a seek jump over the headers of the next member
+        let (vars, rows) = numeric_rows(20);
+        let datasets = [
+            Dataset::new("A", vars.clone(), rows.clone()),
+            Dataset::new("B", vars, rows.clone()),
+        ];
+        let options = WriteOptions {
+            version: XptVersion::V8,
+            ..WriteOptions::default()
+        };
+        let mut data = Vec::new();
+        write_xpt_to_writer_with(&mut data, &datasets, options).unwrap();
+        declare_rows(&mut data, 40);
+
+        let (read, diagnostics) =
+            read_xpt_v5_from_reader_with(data.as_slice(), ReadOptions::default()).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "20 declared observations are missing");
+
+        let mut reader = XptReader::seekable(io::Cursor::new(&data), ReadOptions::default()).unwrap();
+        for name in ["A", "B"] {
+            let mut member = reader.next_member().unwrap().unwrap();
+            assert_eq!(member.info().name, name);
+            assert_eq!(member.skip_rest().unwrap(), 20);
+        }
+        assert!(reader.next_member().unwrap().is_none());
+        assert_eq!(reader.diagnostics(), diagnostics);
 
-/// Read one V5/V6 transport file; return all members (datasets).
-pub fn read_xpt_v5<P: AsRef<Path>>(path: P) -> Result<Vec<Dataset>> {
-    let f = File::open(path)?;
-    let r = BufReader::new(f);
-    read_xpt_v5_from_reader(r)
-}
\ No newline at end of file
+        let mut reader = XptReader::seekable(io::Cursor::new(&data), ReadOptions::default()).unwrap();
+        let member = reader.find_member("B").unwrap().unwrap();
+        assert_eq!(member.into_dataset().unwrap().rows, rows);
+
+        let mapped = crate::MappedXpt::from_bytes(data).unwrap();
+        assert_eq!(mapped.len(), 2);
+        for member in mapped.members() {
+            assert_eq!(member.row_count(), 20);
+            assert_eq!(member.decode_rows(0..20).unwrap(), rows);
+        }
+    }
+
+    #[test]
+    fn huge_declared_count_is_rejected_without_overflow() {
+        let (vars, rows) = numeric_rows(10);
+        let mut data = write(XptVersion::V8, vars, rows.clone());
+        declare_rows(&mut data, 999_999_999_999_999);
+
+        let mapped = crate::MappedXpt::from_bytes(data.clone()).unwrap();
+        assert_eq!(mapped.member(0).unwrap().row_count(), 10);
+        assert!(mapped.diagnostics()[0].message.contains("more than the 160 bytes left"));
+
+        let mut reader = XptReader::new(data.as_slice()).unwrap();
+        let mut member = reader.next_member().unwrap().unwrap();
+        assert_eq!(member.skip_rows(4).unwrap(), 4);
+        assert_eq!(member.skip_rest().unwrap(), 6);
+        assert!(reader.next_member().unwrap().is_none());
+
+        // Too many rows to address at all
+        let header = data
+            .windows(25)
+            .position(|w| w == b"HEADER RECORD*******OBSV8")
+            .unwrap();
+        data[header + 48..header + 80].copy_from_slice(format!("{:<32}", u64::MAX).as_bytes());
+        let (read_rows, _) = read(&data);
+        assert_eq!(read_rows, rows);
+        let mapped = crate::MappedXpt::from_bytes(data).unwrap();
+        assert_eq!(mapped.member(0).unwrap().row_count(), 10);
+    }
+
+    #[test]
+    fn seeking_skips_and_tails_match_reading() {
+        let (vars, rows) = numeric_rows(1000);
+        let blanks = (0..500).map(|i| text(if i % 7 == 0 || i >= 497 { "" } else { "c" }));
+        let datasets = [
+            Dataset::new("NUM", vars, rows),
+            Dataset::new("CHAR", vec![VarMeta::character("C", 8)], blanks.collect()),
+            Dataset::new("LAST", vec![VarMeta::character("C", 100)], vec![text("z"); 3]),
+        ];
+        for version in [XptVersion::V5, XptVersion::V8] {
+            let options = WriteOptions {
+                version,
+                ..WriteOptions::default()
+            };
+            let mut data = Vec::new();
+            write_xpt_to_writer_with(&mut data, &datasets, options).unwrap();
+
+            for skip in [0, 1, 7, 490, 499, 995, 1000, 5000] {
+                let options = ReadOptions {
+                    skip,
+                    ..ReadOptions::default()
+                };
+                let (expected, notes) =
+                    read_xpt_v5_from_reader_with(data.as_slice(), options.clone()).unwrap();
+                let mut reader = XptReader::seekable(io::Cursor::new(&data), options).unwrap();
+                for dataset in &expected {
+                    let member = reader.next_member().unwrap().unwrap();
+                    assert_eq!(member.into_dataset().unwrap().rows, dataset.rows, "skip {}", skip);
+                }
+                assert_eq!(reader.diagnostics(), notes, "skip {}", skip);
+            }
+
+            for n in [0, 1, 3, 20, 600] {
+                let tails = |seekable| {
+                    let mut tails = Vec::new();
+                    let mut reader = match seekable {
+                        true => XptReader::seekable(io::Cursor::new(&data[..]), ReadOptions::default()),
+                        false => XptReader::new(io::Cursor::new(&data[..])),
+                    }
+                    .unwrap();
+                    while let Some(mut member) = reader.next_member().unwrap() {
+                        tails.push(member.tail(n).unwrap());
+                    }
+                    (tails, reader.take_diagnostics())
+                };
+                let (expected, notes) = tails(false);
+                let read = read_xpt_v5_from_reader_with(data.as_slice(), ReadOptions::default());
+                for (tail, dataset) in expected.iter().zip(read.unwrap().0) {
+                    assert_eq!(tail[..], dataset.rows[dataset.rows.len().saturating_sub(n)..]);
+                }
+                assert_eq!(tails(true), (expected, notes), "tail {}", n);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_decoding_reports_the_same_offsets() {
+        let rows: Vec<Vec<Value>> = (0..5000)
+            .map(|i| text(if i == 4000 { "café" } else { "cafe" }))
+            .collect();
+        let options = WriteOptions {
+            encoding: encoding_rs::WINDOWS_1252,
+            ..WriteOptions::default()
+        };
+        let mut data = Vec::new();
+        let dataset = Dataset::new("T", vec![VarMeta::character("C", 8)], rows);
+        write_xpt_to_writer_with(&mut data, &[dataset], options).unwrap();
+
+        for encoding in [TextEncoding::Auto, TextEncoding::Fixed(encoding_rs::UTF_8)] {
+            let read = |parallelism| {
+                let options = ReadOptions {
+                    encoding,
+                    parallelism,
+                    ..ReadOptions::default()
+                };
+                read_xpt_v5_from_reader_with(data.as_slice(), options).unwrap()
+            };
+            let (sequential, expected) = read(Parallelism::Sequential);
+            let (parallel, diagnostics) = read(Parallelism::Threads(4));
+            assert_eq!(parallel[0].rows, sequential[0].rows);
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics, expected);
+        }
+    }
+}